@@ -1,9 +1,13 @@
 // SPDX-License-Identifier: MIT
-use std::{
-    borrow::Cow,
-    fmt::{Debug, Display, Formatter},
-};
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
 
+use alloc::{borrow::Cow, boxed::Box, format, string::String, vec::Vec};
+use core::fmt::{Debug, Display, Formatter};
+
+#[cfg(feature = "std")]
+pub mod logger;
+#[cfg(feature = "std")]
 pub mod mtd;
 
 pub mod v1v2;
@@ -40,49 +44,722 @@ fn slice_find<T: PartialEq<T>>(ts: &[T], t: &T) -> Option<usize> {
     ret
 }
 
+// number of characters of a value shown by the Display impls of variables
+pub const DISPLAY_VALUE_LIMIT: usize = 128;
+
+// escapes non-printable bytes as %xx. with a limit, output stops before the escaped value would
+// exceed that many characters and a suffix says how many bytes were left out.
+pub fn escape_value(value: impl Iterator<Item = u8>, limit: Option<usize>) -> String {
+    let mut out = String::new();
+    let mut value = value.peekable();
+    while let Some(&c) = value.peek() {
+        let printable = (c as char).is_ascii() && !(c as char).is_ascii_control();
+        let width = if printable { 1 } else { 3 };
+        if limit.is_some_and(|limit| out.len() + width > limit) {
+            break;
+        }
+        if printable {
+            out.push(c as char);
+        } else {
+            out.push_str(&format!("%{c:02x}"));
+        }
+        value.next();
+    }
+    let remaining = value.count();
+    if remaining != 0 {
+        out.push_str(&format!("…({remaining} more bytes)"));
+    }
+    out
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut buf = [0; 3];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// whitespace is skipped, plists wrap the data over several lines
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for (i, chunk) in text.chunks(4).enumerate() {
+        let last = i == text.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &b in &chunk[..4 - padding] {
+            n = n << 6 | BASE64.iter().position(|&c| c == b)? as u32;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumKind {
+    // v1v2 bank contents
+    Adler32,
+    // v3 variable values
+    Crc32,
+}
+
+impl Display for ChecksumKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ChecksumKind::Adler32 => write!(f, "adler32"),
+            ChecksumKind::Crc32 => write!(f, "crc32"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
+    // the structure of the image is broken
     ParseError,
+    // the structure is fine, but stored and computed checksums differ
+    ChecksumMismatch {
+        kind: ChecksumKind,
+        expected: u32,
+        actual: u32,
+    },
     SectionTooBig,
+    InvalidBank,
+    // the image is neither v3 nor v1v2, with the error from trying each
+    UnknownFormat {
+        v3: Box<Error>,
+        v1v2: Box<Error>,
+    },
+    // the format can't store variables under this guid
+    UnsupportedGuid,
+    // the operation doesn't exist for this nvram format
+    Unsupported,
+    // the whole image is erased flash, e.g. after an interrupted write
+    Blank,
+    #[cfg(feature = "std")]
     ApplyError(std::io::Error),
+    // erasing before a write failed, nothing was written
+    #[cfg(feature = "std")]
+    EraseError(std::io::Error),
 }
 
-type Result<T> = std::result::Result<T, Error>;
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            Error::ParseError => write!(f, "failed to parse nvram"),
+            Error::ChecksumMismatch {
+                kind,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch: stored 0x{:x}, computed 0x{:x}",
+                kind, expected, actual
+            ),
+            Error::SectionTooBig => write!(f, "ran out of space in nvram"),
+            Error::InvalidBank => write!(f, "nvram bank is not valid"),
+            Error::UnsupportedGuid => write!(f, "variable guid is not supported by this nvram"),
+            Error::Unsupported => write!(f, "operation is not supported by this nvram format"),
+            Error::Blank => write!(f, "nvram is blank"),
+            Error::UnknownFormat { v3, v1v2 } => write!(
+                f,
+                "unrecognized nvram format (as v3: {}, as v1v2: {})",
+                v3, v1v2
+            ),
+            #[cfg(feature = "std")]
+            Error::ApplyError(e) => write!(f, "failed to write nvram: {}", e),
+            #[cfg(feature = "std")]
+            Error::EraseError(e) => write!(f, "failed to erase nvram: {}", e),
+        }
+    }
+}
 
-#[derive(Clone, Copy, PartialEq)]
+type Result<T> = core::result::Result<T, Error>;
+
+const APPLE_COMMON_VARIABLE_GUID: &[u8; 16] = &[
+    0x7C, 0x43, 0x61, 0x10, 0xAB, 0x2A, 0x4B, 0xBB, 0xA8, 0x80, 0xFE, 0x41, 0x99, 0x5C, 0x9F, 0x82,
+];
+const APPLE_SYSTEM_VARIABLE_GUID: &[u8; 16] = &[
+    0x40, 0xA0, 0xDD, 0xD2, 0x77, 0xF8, 0x43, 0x92, 0xB4, 0xA3, 0x1E, 0x73, 0x04, 0x20, 0x65, 0x16,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VarType {
     Common,
     System,
 }
 
+impl VarType {
+    pub fn guid(&self) -> [u8; 16] {
+        match self {
+            VarType::Common => *APPLE_COMMON_VARIABLE_GUID,
+            VarType::System => *APPLE_SYSTEM_VARIABLE_GUID,
+        }
+    }
+
+    pub fn from_guid(guid: &[u8; 16]) -> Option<VarType> {
+        [VarType::Common, VarType::System]
+            .into_iter()
+            .find(|t| t.guid() == *guid)
+    }
+}
+
+// formats a guid the way uuids are usually written, e.g. 7C436110-AB2A-4BBB-A880-FE41995C9F82
+pub fn format_guid(guid: &[u8; 16]) -> String {
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    };
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex(&guid[0..4]),
+        hex(&guid[4..6]),
+        hex(&guid[6..8]),
+        hex(&guid[8..10]),
+        hex(&guid[10..16])
+    )
+}
+
+// inverse of format_guid, case insensitive
+pub fn parse_guid(s: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = s.split('-').collect();
+    if groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12]) {
+        return None;
+    }
+    let hex: String = groups.concat();
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut guid = [0; 16];
+    for (i, b) in guid.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(guid)
+}
+
+// the partition name for apple's guids, the formatted guid for anything else
+pub fn guid_name(guid: &[u8; 16]) -> String {
+    match VarType::from_guid(guid) {
+        Some(typ) => format!("{}", typ),
+        None => format_guid(guid),
+    }
+}
+
 impl Display for VarType {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match *self {
+            VarType::Common => write!(f, "common"),
+            VarType::System => write!(f, "system"),
+        }
+    }
+}
+
+// owned copy of a variable that doesn't borrow from the parsed nvram image.
+// attrs and state only exist in the v3 format
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedVariable {
+    pub partition: VarType,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub guid: [u8; 16],
+    pub attrs: Option<u32>,
+    pub state: Option<u8>,
+}
+
+// partition:name=value with the value escaped and optionally truncated like escape_value. every
+// way of printing a variable goes through this so v1v2 and v3 output stays the same
+fn variable_line(
+    guid: &[u8; 16],
+    key: &[u8],
+    value: impl Iterator<Item = u8>,
+    limit: Option<usize>,
+) -> String {
+    format!(
+        "{}:{}={}",
+        guid_name(guid),
+        String::from_utf8_lossy(key),
+        escape_value(value, limit)
+    )
+}
+
+impl OwnedVariable {
+    pub fn format(&self, limit: Option<usize>) -> String {
+        variable_line(&self.guid, &self.key, self.value.iter().copied(), limit)
+    }
+}
+
+impl Display for OwnedVariable {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.format(Some(DISPLAY_VALUE_LIMIT)))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NvramVersion {
+    V1V2,
+    V3,
+}
+
+#[derive(Debug)]
+pub enum ParseWarning {
+    // the image is valid as both v3 and v1v2, v3 was used
+    AmbiguousFormat,
+    // the newest bank is corrupt, an older generation is active instead
+    NewerBankInvalid { bank: usize, active: usize },
+    // another valid bank has the same generation as the active one, so which of them is newer
+    // is a guess
+    EqualGenerations { bank: usize, active: usize },
+}
+
+impl Display for ParseWarning {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         match self {
-            &VarType::Common => write!(f, "common"),
-            &VarType::System => write!(f, "system"),
+            ParseWarning::AmbiguousFormat => {
+                write!(f, "nvram parses as both v3 and v1v2, using v3")
+            }
+            ParseWarning::NewerBankInvalid { bank, active } => write!(
+                f,
+                "bank {} has the newest generation but is corrupt, using older bank {}",
+                bank, active
+            ),
+            ParseWarning::EqualGenerations { bank, active } => write!(
+                f,
+                "bank {} has the same generation as active bank {}, either may be the newer one",
+                bank, active
+            ),
         }
     }
 }
 
 pub fn nvram_parse<'a>(nvr: &'a [u8]) -> Result<Box<dyn Nvram<'a> + 'a>> {
+    nvram_parse_with_warnings(nvr, &mut Vec::new())
+}
+
+// the variables of the active bank, for callers that already have the nvram contents and don't
+// need to modify them
+pub fn nvram_read(nvr: &[u8]) -> Result<Vec<OwnedVariable>> {
+    Ok(nvram_parse(nvr)?.to_owned_snapshot())
+}
+
+// like nvram_parse, but reports recoverable oddities of the image instead of silently ignoring them
+pub fn nvram_parse_with_warnings<'a>(
+    nvr: &'a [u8],
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<Box<dyn Nvram<'a> + 'a>> {
+    if nvr.iter().all(|&b| b == 0xFF) {
+        return Err(Error::Blank);
+    }
+    let nv = detect_format(nvr, warnings)?;
+    warnings.extend(bank_warnings(nv.as_ref()));
+    Ok(nv)
+}
+
+// warnings about the banks of an already parsed image
+pub fn bank_warnings(nv: &dyn Nvram<'_>) -> Vec<ParseWarning> {
+    let active = nv.active_bank();
+    let mut warnings: Vec<ParseWarning> = nv
+        .newer_invalid_bank()
+        .map(|bank| ParseWarning::NewerBankInvalid { bank, active })
+        .into_iter()
+        .collect();
+    let banks: Vec<_> = nv.banks().collect();
+    let Bank::Valid(active_part) = &banks[active] else {
+        return warnings;
+    };
+    // banks with the same contents can't be served wrongly. this also skips the copy v1v2 puts in
+    // place of a bank that fails to parse
+    for (bank, b) in banks.iter().enumerate() {
+        if let Bank::Valid(p) = b {
+            if bank != active
+                && p.generation() == active_part.generation()
+                && p.history() != active_part.history()
+            {
+                warnings.push(ParseWarning::EqualGenerations { bank, active });
+            }
+        }
+    }
+    warnings
+}
+
+// generations are compared like tcp sequence numbers, so a counter that wrapped around to 0 is
+// still newer than one at u32::MAX
+pub(crate) fn generation_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+// generations for repair_generations, None for banks that aren't valid. the other banks are
+// ordered by how far their generation is behind the active one, a bank that claims to be ahead
+// of it is taken as the oldest
+#[cfg(feature = "std")]
+pub(crate) fn renumbered_generations(
+    generations: &[Option<u32>],
+    active: usize,
+) -> Vec<Option<u32>> {
+    let Some(active_gen) = generations[active] else {
+        return generations.to_vec();
+    };
+    let mut order: Vec<usize> = (0..generations.len())
+        .filter(|&i| generations[i].is_some())
+        .collect();
+    order.sort_by_key(|&i| {
+        let behind = active_gen.wrapping_sub(generations[i].unwrap());
+        (core::cmp::Reverse(behind), i == active, i)
+    });
+    let mut out = generations.to_vec();
+    for (n, i) in order.into_iter().enumerate() {
+        out[i] = Some(n as u32 + 1);
+    }
+    out
+}
+
+fn detect_format<'a>(
+    nvr: &'a [u8],
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<Box<dyn Nvram<'a> + 'a>> {
     match (v3::Nvram::parse(nvr), v1v2::Nvram::parse(nvr)) {
-        (Ok(nvram_v3), Err(_)) => Ok(Box::new(nvram_v3)),
-        (Err(_), Ok(nvram_v1v2)) => Ok(Box::new(nvram_v1v2)),
-        _ => Err(Error::ParseError),
+        (Ok(nvram_v3), Err(e)) => {
+            log::debug!("detected v3 nvram, v1v2 parse failed: {}", e);
+            Ok(Box::new(nvram_v3))
+        }
+        (Err(e), Ok(nvram_v1v2)) => {
+            log::debug!("detected v1v2 nvram, v3 parse failed: {}", e);
+            Ok(Box::new(nvram_v1v2))
+        }
+        // v3 is the newer format, an image that also looks like v1v2 is most likely v3
+        (Ok(nvram_v3), Ok(_)) => {
+            log::debug!("nvram parses as both v3 and v1v2");
+            warnings.push(ParseWarning::AmbiguousFormat);
+            Ok(Box::new(nvram_v3))
+        }
+        (Err(v3), Err(v1v2)) => Err(Error::UnknownFormat {
+            v3: Box::new(v3),
+            v1v2: Box::new(v1v2),
+        }),
+    }
+}
+
+// skips format detection, for images that are known to be of one format. errors come from that
+// format's parser instead of being wrapped in UnknownFormat.
+pub fn nvram_parse_as<'a>(nvr: &'a [u8], version: NvramVersion) -> Result<Box<dyn Nvram<'a> + 'a>> {
+    match version {
+        NvramVersion::V1V2 => Ok(Box::new(v1v2::Nvram::parse(nvr)?)),
+        NvramVersion::V3 => Ok(Box::new(v3::Nvram::parse(nvr)?)),
+    }
+}
+
+// saves a raw nvram image before it is modified. without a path, a timestamped file in the
+// temporary directory is used. returns the path the image was written to.
+#[cfg(feature = "std")]
+pub fn write_backup(
+    data: &[u8],
+    path: Option<&std::path::Path>,
+) -> std::io::Result<std::path::PathBuf> {
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => {
+            let time = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            std::env::temp_dir().join(format!("nvram-backup-{}.bin", time.as_secs()))
+        }
+    };
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+// reads only the store headers and the active bank of a v3 nvram, and everything for other
+// formats. the result parses like a full image, but with a single bank, so it's only good for
+// reading variables.
+#[cfg(feature = "std")]
+pub fn read_active_bank<R: std::io::Read + std::io::Seek>(r: &mut R) -> std::io::Result<Vec<u8>> {
+    if let Some(bank) = v3::read_active_bank(r)? {
+        return Ok(bank);
     }
+    let mut data = Vec::new();
+    r.seek(std::io::SeekFrom::Start(0))?;
+    r.read_to_end(&mut data)?;
+    Ok(data)
 }
 
+#[cfg(feature = "std")]
 pub trait NvramWriter {
-    fn erase_if_needed(&mut self, offset: u32, size: usize);
+    fn erase_if_needed(&mut self, offset: u32, size: usize) -> std::io::Result<()>;
     fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()>;
+    // what is currently stored in the region, None for writers that can't read it back
+    fn read_back(&mut self, _offset: u32, _size: usize) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    // erases the region and writes buf to it, but leaves out the erase when buf only clears bits
+    // of what is stored there, and everything when nothing changes. that relies on programming
+    // being able to clear bits of already written data, which holds for the nor flash nvram is
+    // on but not for nand with ecc. returns whether the region was erased.
+    fn write_minimal(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<bool> {
+        if let Some(old) = self.read_back(offset, buf.len())? {
+            if old == buf {
+                return Ok(false);
+            }
+            if old.iter().zip(buf).all(|(o, n)| o & n == *n) {
+                self.write_all(offset, buf)?;
+                return Ok(false);
+            }
+        }
+        self.erase_if_needed(offset, buf.len())?;
+        self.write_all(offset, buf)?;
+        Ok(true)
+    }
+}
+
+// turns an erase followed by a write of the same region into write_minimal, so rewriting a bank
+// with (mostly) the same contents doesn't cost an erase cycle. the formats always write the
+// region they erase right away, an erase that isn't followed by such a write is done as asked.
+#[cfg(feature = "std")]
+pub struct MinimalEraseWriter<'w> {
+    inner: &'w mut dyn NvramWriter,
+    pending_erase: Option<(u32, usize)>,
+    pub skipped_erases: usize,
+}
+
+#[cfg(feature = "std")]
+impl<'w> MinimalEraseWriter<'w> {
+    pub fn new(inner: &'w mut dyn NvramWriter) -> Self {
+        Self {
+            inner,
+            pending_erase: None,
+            skipped_erases: 0,
+        }
+    }
+
+    fn flush_erase(&mut self) -> std::io::Result<()> {
+        match self.pending_erase.take() {
+            Some((offset, size)) => self.inner.erase_if_needed(offset, size),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl NvramWriter for MinimalEraseWriter<'_> {
+    fn erase_if_needed(&mut self, offset: u32, size: usize) -> std::io::Result<()> {
+        self.flush_erase()?;
+        self.pending_erase = Some((offset, size));
+        Ok(())
+    }
+
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        if self.pending_erase == Some((offset, buf.len())) {
+            self.pending_erase = None;
+            if !self.inner.write_minimal(offset, buf)? {
+                self.skipped_erases += 1;
+            }
+            return Ok(());
+        }
+        self.flush_erase()?;
+        self.inner.write_all(offset, buf)
+    }
+
+    fn read_back(&mut self, offset: u32, size: usize) -> std::io::Result<Option<Vec<u8>>> {
+        self.flush_erase()?;
+        self.inner.read_back(offset, size)
+    }
+}
+
+// counts how many bytes an operation asks the inner writer to erase and write
+#[cfg(feature = "std")]
+pub struct CountingWriter<'w> {
+    inner: &'w mut dyn NvramWriter,
+    pub erased: usize,
+    pub written: usize,
+}
+
+#[cfg(feature = "std")]
+impl<'w> CountingWriter<'w> {
+    pub fn new(inner: &'w mut dyn NvramWriter) -> Self {
+        Self {
+            inner,
+            erased: 0,
+            written: 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl NvramWriter for CountingWriter<'_> {
+    fn erase_if_needed(&mut self, offset: u32, size: usize) -> std::io::Result<()> {
+        self.inner.erase_if_needed(offset, size)?;
+        self.erased += size;
+        Ok(())
+    }
+
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        self.written += buf.len();
+        self.inner.write_all(offset, buf)
+    }
+
+    fn read_back(&mut self, offset: u32, size: usize) -> std::io::Result<Option<Vec<u8>>> {
+        self.inner.read_back(offset, size)
+    }
+}
+
+// emulates flash in memory, writes can only clear bits until the region is erased. useful to test
+// or preview changes without touching the device
+#[cfg(feature = "std")]
+pub struct MemoryWriter {
+    pub data: Vec<u8>,
+    pub erase_count: usize,
+}
+
+#[cfg(feature = "std")]
+impl MemoryWriter {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            erase_count: 0,
+        }
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn range(&self, offset: u32, size: usize) -> std::io::Result<core::ops::Range<usize>> {
+        let start = offset as usize;
+        match start.checked_add(size) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl NvramWriter for MemoryWriter {
+    fn erase_if_needed(&mut self, offset: u32, size: usize) -> std::io::Result<()> {
+        let range = self.range(offset, size)?;
+        self.data[range].fill(0xFF);
+        self.erase_count += 1;
+        Ok(())
+    }
+
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        let range = self.range(offset, buf.len())?;
+        for (d, s) in self.data[range].iter_mut().zip(buf) {
+            *d &= s;
+        }
+        Ok(())
+    }
+
+    fn read_back(&mut self, offset: u32, size: usize) -> std::io::Result<Option<Vec<u8>>> {
+        let range = self.range(offset, size)?;
+        Ok(Some(self.data[range].to_vec()))
+    }
+}
+
+pub enum Bank<'b, 'a> {
+    Valid(&'b dyn Partition<'a>),
+    Invalid,
+    Empty,
 }
 
 pub trait Nvram<'a> {
+    // call once before changing the active partition for a write, and write with apply. v1v2
+    // copies the active partition into the other bank and edits the copy, keeping the old one
+    // as a fallback. v3 appends changes within the bank and only picks a new bank in apply,
+    // so there is nothing to prepare.
     fn prepare_for_write(&mut self);
     fn active_part_mut(&mut self) -> &mut dyn Partition<'a>;
     fn partitions(&self) -> Box<dyn Iterator<Item = &dyn Partition<'a>> + '_>;
+    fn banks(&self) -> Box<dyn Iterator<Item = Bank<'_, 'a>> + '_>;
+    fn active_bank(&self) -> usize;
+    // makes a valid bank the active one, for reading it or writing on top of it
+    fn set_active(&mut self, index: usize) -> Result<()>;
+    // size of a single bank in the serialized image
+    fn bank_size(&self) -> usize;
+    // a bank that failed to parse but whose header has a newer generation than the active bank,
+    // i.e. the bank that should have been active. the data read is then older than expected.
+    fn newer_invalid_bank(&self) -> Option<usize>;
+    // whether apply would have to move the pending changes to another bank, which costs an
+    // erase of that bank on v3. v1v2 writes every change to the other bank, so it's always true.
+    fn would_switch_bank(&self) -> bool;
     fn serialize(&self) -> Result<Vec<u8>>;
+    // owned copies of the variables in the active partition
+    fn to_owned_snapshot(&self) -> Vec<OwnedVariable>;
+    #[cfg(feature = "std")]
     fn apply(&mut self, w: &mut dyn NvramWriter) -> Result<()>;
+    // like apply, but overwrites the old values instead of appending new copies when every
+    // change keeps the length of an existing variable. falls back to apply otherwise.
+    // not crash safe: v3 erases the active bank and writes it again, losing the bank if power
+    // is cut in between, and it costs an erase every time. apply only erases once a bank is full.
+    #[cfg(feature = "std")]
+    fn apply_in_place(&mut self, w: &mut dyn NvramWriter) -> Result<()>;
+    // state and flags bytes of the active bank's v3 store header, None for v1v2
+    fn store_header_flags(&self) -> Option<(u8, u8)>;
+    // overwrites the state and flags bytes and rewrites the active bank. what they mean is only
+    // partly known, this exists to experiment with how firmware reacts to them.
+    #[cfg(feature = "std")]
+    fn set_store_header_flags(
+        &mut self,
+        w: &mut dyn NvramWriter,
+        state: u8,
+        flags: u8,
+    ) -> Result<()>;
+    // renumbers the generations of the valid banks from 1 in the order they were written, the
+    // active bank last, and rewrites the banks that changed. fixes images whose counters wrapped
+    // around or tie, which firmware may not order the way this crate does.
+    #[cfg(feature = "std")]
+    fn repair_generations(&mut self, w: &mut dyn NvramWriter) -> Result<()>;
+
+    // value of a variable in the active partition
+    fn get(&self, typ: VarType, key: &[u8]) -> Option<Vec<u8>> {
+        match self.banks().nth(self.active_bank())? {
+            Bank::Valid(p) => p.get_variable(key, typ).map(|v| v.value().into_owned()),
+            _ => None,
+        }
+    }
+    // sets a single variable and writes the result
+    #[cfg(feature = "std")]
+    fn set(
+        &mut self,
+        w: &mut dyn NvramWriter,
+        typ: VarType,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        self.prepare_for_write();
+        self.active_part_mut()
+            .insert_variable(key, Cow::Owned(value.to_vec()), typ);
+        self.apply(w)
+    }
+    // deletes a single variable and writes the result, nothing is written if it doesn't exist
+    #[cfg(feature = "std")]
+    fn delete(&mut self, w: &mut dyn NvramWriter, typ: VarType, key: &[u8]) -> Result<()> {
+        if self.get(typ, key).is_none() {
+            return Ok(());
+        }
+        self.prepare_for_write();
+        self.active_part_mut().remove_variable(key, typ);
+        self.apply(w)
+    }
 }
 
 pub trait Partition<'a>: Display {
@@ -90,8 +767,609 @@ pub trait Partition<'a>: Display {
     fn get_variable(&self, key: &[u8], typ: VarType) -> Option<&dyn Variable<'a>>;
     fn insert_variable(&mut self, key: &[u8], value: Cow<'a, [u8]>, typ: VarType);
     fn remove_variable(&mut self, key: &[u8], typ: VarType);
+    // same as the above, but for variables under any vendor guid. v1v2 only knows the apple
+    // common and system guids.
+    fn get_variable_by_guid(&self, key: &[u8], guid: &[u8; 16]) -> Option<&dyn Variable<'a>>;
+    fn insert_variable_by_guid(
+        &mut self,
+        key: &[u8],
+        value: Cow<'a, [u8]>,
+        guid: &[u8; 16],
+    ) -> Result<()>;
+    fn remove_variable_by_guid(&mut self, key: &[u8], guid: &[u8; 16]);
+    // space left for variables of the given type
+    fn free_bytes(&self, typ: VarType) -> usize;
+    // space left for variables of any type, for v3 this is what can be appended before the
+    // next write has to move to another bank
+    fn free_bytes_total(&self) -> usize;
+    // space a variable with this key and value takes up once written
+    fn variable_size(&self, key: &[u8], value: &[u8]) -> usize;
+    // variables whose stored checksum doesn't match their contents
+    fn verify_checksums(&self) -> Vec<&dyn Variable<'a>>;
+    // fixes up stored checksums after the structure was edited by hand
+    fn recompute_checksums(&mut self);
+    // old or deleted copies of variables that are still stored, oldest first
+    fn stale_variables(&self) -> Vec<OwnedVariable>;
+    // counter in the bank header, increased every time a write moves to another bank
+    fn generation(&self) -> u32;
+    // every stored copy of every variable in the order it was written. neither format stores
+    // timestamps, this order and the generation of the bank are the only hint of when a value
+    // was written. v1v2 rewrites the whole bank and only has the current values.
+    fn history(&self) -> Vec<OwnedVariable>;
 }
 
 pub trait Variable<'a>: Display {
     fn value(&self) -> Cow<'a, [u8]>;
+    // iterates over the value without allocating a copy of it
+    fn value_bytes(&self) -> Box<dyn Iterator<Item = u8> + '_>;
+    fn value_len(&self) -> usize {
+        self.value_bytes().count()
+    }
+    fn value_eq(&self, other: &[u8]) -> bool {
+        self.value_bytes().eq(other.iter().copied())
+    }
+    fn key(&self) -> &[u8];
+    fn typ(&self) -> VarType;
+    fn guid(&self) -> [u8; 16] {
+        self.typ().guid()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    pub(crate) fn empty_v3_nvram(bank_count: usize) -> Vec<u8> {
+        v3::Nvram::empty(bank_count, 0x4000, 0xC000)
+    }
+
+    pub(crate) fn empty_v1v2_nvram() -> Vec<u8> {
+        v1v2::Nvram::empty(0x7FE0, 0x8000).unwrap()
+    }
+
+    type VarSet = Vec<(String, Vec<u8>, Vec<u8>)>;
+
+    fn var_set(nv: &mut dyn Nvram) -> VarSet {
+        let mut vars: VarSet = nv
+            .active_part_mut()
+            .variables()
+            .map(|v| (v.typ().to_string(), v.key().to_vec(), v.value().to_vec()))
+            .collect();
+        vars.sort();
+        vars
+    }
+
+    fn round_trip(image: Vec<u8>) -> Result<VarSet> {
+        let mut nvr = MemoryWriter::new(image);
+
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        nv.prepare_for_write();
+        let part = nv.active_part_mut();
+        part.insert_variable(b"keep", Cow::Borrowed(b"value"), VarType::System);
+        part.insert_variable(b"update", Cow::Borrowed(b"old"), VarType::Common);
+        part.insert_variable(b"remove", Cow::Borrowed(b"gone"), VarType::System);
+        part.insert_variable(
+            b"binary",
+            Cow::Borrowed(&[0, 1, 0xFF, 0xFF, 0]),
+            VarType::System,
+        );
+        // same key in the other section must stay independent
+        part.insert_variable(b"keep", Cow::Borrowed(b"common"), VarType::Common);
+        nv.apply(&mut nvr)?;
+
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        nv.prepare_for_write();
+        let part = nv.active_part_mut();
+        part.insert_variable(b"update", Cow::Borrowed(b"new"), VarType::Common);
+        part.remove_variable(b"remove", VarType::System);
+        nv.apply(&mut nvr)?;
+
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        Ok(var_set(nv.as_mut()))
+    }
+
+    fn expected_vars() -> VarSet {
+        let mut vars: VarSet = vec![
+            ("system".into(), b"keep".to_vec(), b"value".to_vec()),
+            ("common".into(), b"update".to_vec(), b"new".to_vec()),
+            (
+                "system".into(),
+                b"binary".to_vec(),
+                vec![0, 1, 0xFF, 0xFF, 0],
+            ),
+            ("common".into(), b"keep".to_vec(), b"common".to_vec()),
+        ];
+        vars.sort();
+        vars
+    }
+
+    #[test]
+    fn test_parse_dispatch() -> Result<()> {
+        let v3 = empty_v3_nvram(2);
+        assert_eq!(nvram_parse(&v3)?.banks().count(), 2);
+        assert!(v3::Nvram::parse(&v3).is_ok());
+        assert!(v1v2::Nvram::parse(&v3).is_err());
+
+        let v1v2 = empty_v1v2_nvram();
+        assert!(nvram_parse(&v1v2).is_ok());
+        assert!(v3::Nvram::parse(&v1v2).is_err());
+        assert!(v1v2::Nvram::parse(&v1v2).is_ok());
+
+        // erased flash is reported separately from garbage
+        assert!(matches!(nvram_parse(&[0xFF; 0x20000]), Err(Error::Blank)));
+        let mut data = vec![0xFF; 0x20000];
+        data[0x100] = 0;
+        assert!(matches!(
+            nvram_parse(&data),
+            Err(Error::UnknownFormat { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_as() -> Result<()> {
+        let v3 = empty_v3_nvram(2);
+        assert_eq!(nvram_parse_as(&v3, NvramVersion::V3)?.banks().count(), 2);
+        assert!(matches!(
+            nvram_parse_as(&v3, NvramVersion::V1V2),
+            Err(Error::ParseError)
+        ));
+        let v1v2 = empty_v1v2_nvram();
+        assert!(nvram_parse_as(&v1v2, NvramVersion::V1V2).is_ok());
+        assert!(matches!(
+            nvram_parse_as(&v1v2, NvramVersion::V3),
+            Err(Error::ParseError)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ambiguous() -> Result<()> {
+        // a v3 bank followed by a v1v2 partition in the second half
+        let mut data = empty_v3_nvram(2);
+        data[0x10000..].copy_from_slice(&empty_v1v2_nvram()[..0x10000]);
+        assert!(v1v2::Nvram::parse(&data).is_ok());
+
+        let mut warnings = Vec::new();
+        let nv = nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert!(matches!(warnings[..], [ParseWarning::AmbiguousFormat]));
+        assert_eq!(nv.bank_size(), 0x10000);
+        assert!(matches!(nv.banks().nth(1), Some(Bank::Invalid)));
+        Ok(())
+    }
+
+    fn check_convenience_methods(image: Vec<u8>) -> Result<()> {
+        let mut nvr = MemoryWriter::new(image);
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        nv.set(&mut nvr, VarType::System, b"key", b"value")?;
+        nv.set(&mut nvr, VarType::Common, b"key", b"other")?;
+        nv.set(&mut nvr, VarType::System, b"key", b"new")?;
+        assert_eq!(nv.get(VarType::System, b"key"), Some(b"new".to_vec()));
+
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        assert_eq!(nv.get(VarType::System, b"key"), Some(b"new".to_vec()));
+        assert_eq!(nv.get(VarType::Common, b"key"), Some(b"other".to_vec()));
+        nv.delete(&mut nvr, VarType::System, b"key")?;
+        nv.delete(&mut nvr, VarType::System, b"missing")?;
+
+        let data = nvr.get_data().to_owned();
+        let nv = nvram_parse(&data)?;
+        assert_eq!(nv.get(VarType::System, b"key"), None);
+        assert_eq!(nv.get(VarType::Common, b"key"), Some(b"other".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_convenience_methods() -> Result<()> {
+        check_convenience_methods(empty_v1v2_nvram())?;
+        check_convenience_methods(empty_v3_nvram(2))
+    }
+
+    #[test]
+    fn test_counting_writer() -> Result<()> {
+        let mut nvr = MemoryWriter::new(empty_v3_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        let mut w = CountingWriter::new(&mut nvr);
+        nv.set(&mut w, VarType::System, b"key", b"value")?;
+        assert_eq!((w.erased, w.written), (0, 0x10000));
+        nv.active_part_mut()
+            .insert_variable(b"key", Cow::Borrowed(b"eulav"), VarType::System);
+        nv.apply_in_place(&mut w)?;
+        assert_eq!((w.erased, w.written), (0x10000, 0x20000));
+        assert_eq!(nvr.erase_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_minimal() -> Result<()> {
+        let mut nvr = MemoryWriter::new(vec![0xF0; 4]);
+        assert!(!nvr.write_minimal(0, &[0x70; 4]).unwrap());
+        assert!(!nvr.write_minimal(0, &[0x70; 4]).unwrap());
+        assert_eq!((nvr.get_data(), nvr.erase_count), (&[0x70; 4][..], 0));
+        assert!(nvr.write_minimal(0, &[0x0F; 4]).unwrap());
+        assert_eq!((nvr.get_data(), nvr.erase_count), (&[0x0F; 4][..], 1));
+
+        let mut nvr = MemoryWriter::new(empty_v3_nvram(2));
+        let data = nvr.get_data().to_owned();
+        nvram_parse(&data)?.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let rewrite = |nvr: &mut MemoryWriter, value: &'static [u8]| -> Result<usize> {
+            let data = nvr.get_data().to_owned();
+            let mut nv = nvram_parse(&data)?;
+            nv.active_part_mut()
+                .insert_variable(b"key", Cow::Borrowed(value), VarType::System);
+            let mut w = MinimalEraseWriter::new(nvr);
+            nv.apply_in_place(&mut w)?;
+            Ok(w.skipped_erases)
+        };
+        // writing the same value again doesn't touch the flash
+        assert_eq!(rewrite(&mut nvr, b"value")?, 1);
+        assert_eq!(nvr.erase_count, 0);
+        // the new value and its crc need bits set
+        assert_eq!(rewrite(&mut nvr, b"vblue")?, 0);
+        assert_eq!(nvr.erase_count, 1);
+        assert_eq!(
+            nvram_parse(nvr.get_data())?.get(VarType::System, b"key"),
+            Some(b"vblue".to_vec())
+        );
+        Ok(())
+    }
+
+    struct FailingEraseWriter(usize);
+
+    impl NvramWriter for FailingEraseWriter {
+        fn erase_if_needed(&mut self, _offset: u32, _size: usize) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        }
+
+        fn write_all(&mut self, _offset: u32, buf: &[u8]) -> std::io::Result<()> {
+            self.0 += buf.len();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_erase_error() -> Result<()> {
+        let image = empty_v1v2_nvram();
+        let mut nv = nvram_parse(&image)?;
+        let mut w = FailingEraseWriter(0);
+        let res = nv.set(&mut w, VarType::System, b"key", b"value");
+        assert!(matches!(res, Err(Error::EraseError(_))));
+        assert_eq!(w.0, 0);
+
+        let image = empty_v3_nvram(2);
+        let mut nv = nvram_parse(&image)?;
+        let res = nv.set_store_header_flags(&mut w, 0xFE, 0x5A);
+        assert!(matches!(res, Err(Error::EraseError(_))));
+        assert_eq!(w.0, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_v1v2() -> Result<()> {
+        assert_eq!(round_trip(empty_v1v2_nvram())?, expected_vars());
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_v3() -> Result<()> {
+        assert_eq!(round_trip(empty_v3_nvram(2))?, expected_vars());
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_formats_agree() -> Result<()> {
+        assert_eq!(
+            round_trip(empty_v1v2_nvram())?,
+            round_trip(empty_v3_nvram(2))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_guid_format() {
+        let guid = VarType::System.guid();
+        assert_eq!(format_guid(&guid), "40A0DDD2-77F8-4392-B4A3-1E7304206516");
+        assert_eq!(
+            parse_guid("40a0ddd2-77f8-4392-b4a3-1e7304206516"),
+            Some(guid)
+        );
+        assert_eq!(VarType::from_guid(&guid), Some(VarType::System));
+        assert_eq!(parse_guid("40A0DDD2-77F8-4392-B4A3"), None);
+        assert_eq!(parse_guid("40A0DDD2-77F8-4392-B4A3-1E730420651G"), None);
+        assert_eq!(parse_guid("+0A0DDD2-77F8-4392-B4A3-1E7304206516"), None);
+    }
+
+    #[test]
+    fn test_nvram_read() -> Result<()> {
+        for image in [empty_v1v2_nvram(), empty_v3_nvram(2)] {
+            let mut nvr = MemoryWriter::new(image);
+            let data = nvr.get_data().to_owned();
+            let mut nv = nvram_parse(&data)?;
+            nv.set(&mut nvr, VarType::Common, b"key", &[b'v', 0xFF])?;
+            let vars = nvram_read(nvr.get_data())?;
+            let lines: Vec<String> = vars.iter().map(|v| v.to_string()).collect();
+            assert_eq!(lines, ["common:key=v%ff"]);
+            let long = OwnedVariable {
+                value: vec![b'a'; DISPLAY_VALUE_LIMIT + 1],
+                ..vars[0].clone()
+            };
+            assert!(long.to_string().ends_with("…(1 more bytes)"));
+            assert!(long.format(None).ends_with('a'));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_formats_agree() -> Result<()> {
+        let long = [b'x'; DISPLAY_VALUE_LIMIT * 2];
+        let values: [&[u8]; 3] = [b"printable value", &[b'a', 0, 0xFF, 0xFF, b'b'], &long];
+        let mut lines = Vec::new();
+        for image in [empty_v1v2_nvram(), empty_v3_nvram(2)] {
+            let mut nv = nvram_parse(&image)?;
+            let part = nv.active_part_mut();
+            for (i, value) in values.iter().enumerate() {
+                let key = format!("key{}", i);
+                part.insert_variable(key.as_bytes(), Cow::Borrowed(value), VarType::System);
+            }
+            let mut shown: Vec<String> = part.variables().map(|v| v.to_string()).collect();
+            shown.sort();
+            lines.push(shown);
+        }
+        assert_eq!(lines[0], lines[1]);
+        assert_eq!(lines[0][0], "system:key0=printable value");
+        Ok(())
+    }
+
+    // corrupts the value of a variable in the given bank so the bank fails to parse
+    fn corrupt_value(data: &mut [u8], bank: usize, value: &[u8]) {
+        let bank = &mut data[bank * 0x10000..(bank + 1) * 0x10000];
+        let pos = bank.windows(value.len()).position(|w| w == value).unwrap();
+        bank[pos] ^= 1;
+    }
+
+    #[test]
+    fn test_newer_bank_invalid() -> Result<()> {
+        let mut nvr = MemoryWriter::new(empty_v1v2_nvram());
+        let data = nvr.get_data().to_owned();
+        nvram_parse(&data)?.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let mut data = nvr.get_data().to_owned();
+        let written = nvram_parse(&data)?.active_bank();
+        corrupt_value(&mut data, written, b"value");
+        let mut warnings = Vec::new();
+        let nv = nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert_eq!(nv.newer_invalid_bank(), Some(written));
+        assert!(matches!(
+            warnings[..],
+            [ParseWarning::NewerBankInvalid { bank, .. }] if bank == written
+        ));
+
+        // v3 only moves to a new bank when the old one is full, copy the bank instead
+        let mut nvr = MemoryWriter::new(empty_v3_nvram(2));
+        let data = nvr.get_data().to_owned();
+        nvram_parse(&data)?.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let mut data = nvr.get_data().to_owned();
+        data.copy_within(0..0x10000, 0x10000);
+        data[0x10008..0x1000C].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(nvram_parse(&data)?.active_bank(), 1);
+        corrupt_value(&mut data, 1, b"value");
+        let mut warnings = Vec::new();
+        let nv = nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert_eq!(nv.active_bank(), 0);
+        assert!(matches!(
+            warnings[..],
+            [ParseWarning::NewerBankInvalid { bank: 1, active: 0 }]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_equal_generations() -> Result<()> {
+        let mut nvr = MemoryWriter::new(empty_v3_nvram(2));
+        let data = nvr.get_data().to_owned();
+        nvram_parse(&data)?.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let mut data = nvr.get_data().to_owned();
+        data.copy_within(0..0x10000, 0x10000);
+        // an identical copy can't be served wrongly
+        let mut warnings = Vec::new();
+        nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert!(warnings.is_empty());
+
+        let mut nvr = MemoryWriter::new(data.clone());
+        let mut nv = nvram_parse(&data)?;
+        nv.set_active(1)?;
+        nv.set(&mut nvr, VarType::System, b"key", b"other")?;
+        let data = nvr.get_data().to_owned();
+        let mut warnings = Vec::new();
+        let mut nv = nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert_eq!(nv.active_bank(), 0);
+        assert!(matches!(
+            warnings[..],
+            [ParseWarning::EqualGenerations { bank: 1, active: 0 }]
+        ));
+        nv.repair_generations(&mut nvr)?;
+        let data = nvr.get_data().to_owned();
+        let mut warnings = Vec::new();
+        let nv = nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert!(warnings.is_empty());
+        assert_eq!(nv.active_bank(), 0);
+        assert_eq!(nv.get(VarType::System, b"key").unwrap(), b"value");
+        let generations: Vec<u32> = nv.partitions().map(|p| p.generation()).collect();
+        assert_eq!(generations, [2, 1]);
+
+        // v1v2 keeps using bank 1 on a tie
+        let data = empty_v1v2_nvram();
+        let mut nv = v1v2::Nvram::parse(&data)?;
+        nv.partitions[0].generation = 5;
+        nv.partitions[1].generation = 5;
+        nv.partitions[0].insert_variable(b"key", Cow::Borrowed(b"value"), VarType::System);
+        let data = nv.serialize()?;
+        let mut warnings = Vec::new();
+        let nv = nvram_parse_with_warnings(&data, &mut warnings)?;
+        assert_eq!(nv.active_bank(), 1);
+        assert!(matches!(
+            warnings[..],
+            [ParseWarning::EqualGenerations { bank: 0, active: 1 }]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrapped_generations() -> Result<()> {
+        let mut nvr = MemoryWriter::new(empty_v3_nvram(2));
+        let data = nvr.get_data().to_owned();
+        nvram_parse(&data)?.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let mut data = nvr.get_data().to_owned();
+        data.copy_within(0..0x10000, 0x10000);
+        data[0x8..0xC].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[0x10008..0x1000C].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(nvram_parse(&data)?.active_bank(), 1);
+        let mut bank = std::io::Cursor::new(&data);
+        assert_eq!(read_active_bank(&mut bank).unwrap(), data[0x10000..]);
+
+        let mut nvr = MemoryWriter::new(data.clone());
+        nvram_parse(&data)?.repair_generations(&mut nvr)?;
+        let nv = nvram_parse(nvr.get_data())?;
+        assert_eq!(nv.active_bank(), 1);
+        let generations: Vec<u32> = nv.partitions().map(|p| p.generation()).collect();
+        assert_eq!(generations, [1, 2]);
+
+        let data = empty_v1v2_nvram();
+        let mut nv = v1v2::Nvram::parse(&data)?;
+        nv.partitions[0].generation = u32::MAX - 1;
+        nv.partitions[1].generation = u32::MAX;
+        let data = nv.serialize()?;
+        let mut nvr = MemoryWriter::new(data.clone());
+        let mut nv = nvram_parse(&data)?;
+        assert_eq!(nv.active_bank(), 1);
+        // the counter wraps to 0, which is still the newest
+        nv.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        assert_eq!(nv.active_bank(), 0);
+        assert_eq!(nv.partitions().next().unwrap().generation(), 0);
+
+        nv.repair_generations(&mut nvr)?;
+        let nv = nvram_parse(nvr.get_data())?;
+        assert_eq!(nv.active_bank(), 0);
+        assert_eq!(nv.get(VarType::System, b"key").unwrap(), b"value");
+        let generations: Vec<u32> = nv.partitions().map(|p| p.generation()).collect();
+        assert_eq!(generations, [2, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_mismatch() -> Result<()> {
+        let mut nvr = MemoryWriter::new(empty_v3_nvram(1));
+        let data = nvr.get_data().to_owned();
+        nvram_parse(&data)?.set(&mut nvr, VarType::System, b"key", b"value")?;
+        let mut data = nvr.get_data().to_owned();
+        corrupt_value(&mut data, 0, b"value");
+        assert!(matches!(
+            nvram_parse_as(&data, NvramVersion::V3),
+            Err(Error::ChecksumMismatch {
+                kind: ChecksumKind::Crc32,
+                expected,
+                actual
+            }) if expected == crc32fast::hash(b"value") && actual == crc32fast::hash(b"walue")
+        ));
+
+        let mut data = empty_v1v2_nvram();
+        data[16] ^= 1;
+        data[0x10000 + 16] ^= 1;
+        assert!(matches!(
+            nvram_parse_as(&data, NvramVersion::V1V2),
+            Err(Error::ChecksumMismatch {
+                kind: ChecksumKind::Adler32,
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_value_limit() {
+        let value = [b'a', 0, b'b', 0xFF, b'c'];
+        assert_eq!(escape_value(value.iter().copied(), None), "a%00b%ffc");
+        assert_eq!(escape_value(value.iter().copied(), Some(9)), "a%00b%ffc");
+        // the limit never splits an escaped byte
+        assert_eq!(
+            escape_value(value.iter().copied(), Some(7)),
+            "a%00b…(2 more bytes)"
+        );
+        assert_eq!(
+            escape_value([b'x'; 200].into_iter(), Some(DISPLAY_VALUE_LIMIT)),
+            "x".repeat(128) + "…(72 more bytes)"
+        );
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(&[0xff, 0xfe, 0x00, 0x3e]), "//4APg==");
+    }
+
+    #[test]
+    fn test_base64_decode() {
+        for data in [
+            &b""[..],
+            b"f",
+            b"fo",
+            b"foo",
+            b"foob",
+            &[0xff, 0xfe, 0x00, 0x3e],
+        ] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_decode("Zm9v\n\tYmFy").unwrap(), b"foobar");
+        assert_eq!(base64_decode("Zg"), None);
+        assert_eq!(base64_decode("Zg==Zg=="), None);
+        assert_eq!(base64_decode("Z==="), None);
+        assert_eq!(base64_decode("Zm9*"), None);
+    }
+
+    fn check_free_bytes(image: Vec<u8>) -> Result<()> {
+        let mut nvr = MemoryWriter::new(image);
+        let data = nvr.get_data().to_owned();
+        let mut nv = nvram_parse(&data)?;
+        nv.prepare_for_write();
+        let part = nv.active_part_mut();
+        let free = part.free_bytes(VarType::System);
+        let total = part.free_bytes_total();
+        let common = part.free_bytes(VarType::Common);
+        part.insert_variable(b"a", Cow::Borrowed(b"\0b"), VarType::System);
+        let used = part.variable_size(b"a", b"\0b");
+        assert_eq!(part.free_bytes(VarType::System), free - used);
+        assert_eq!(part.free_bytes(VarType::Common), common);
+        assert_eq!(part.free_bytes_total(), total - used);
+
+        // a value using up exactly the remaining space still fits, one more byte doesn't
+        let len = part.free_bytes(VarType::System) - part.variable_size(b"fill", b"");
+        part.insert_variable(b"fill", Cow::Owned(vec![b'x'; len]), VarType::System);
+        assert_eq!(part.free_bytes(VarType::System), 0);
+        nv.apply(&mut nvr)?;
+
+        nv.prepare_for_write();
+        nv.active_part_mut().insert_variable(
+            b"fill",
+            Cow::Owned(vec![b'x'; len + 1]),
+            VarType::System,
+        );
+        assert!(matches!(nv.apply(&mut nvr), Err(Error::SectionTooBig)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_free_bytes() -> Result<()> {
+        check_free_bytes(empty_v1v2_nvram())?;
+        check_free_bytes(empty_v3_nvram(2))
+    }
 }
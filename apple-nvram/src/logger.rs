@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: MIT
+// minimal stderr logger shared by the tools. the level follows the number of -v flags given,
+// ASAHI_NVRAM_LOG=error|warn|info|debug|trace overrides it.
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{}: {}",
+                record.level().as_str().to_lowercase(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init(verbosity: u8) {
+    let level = std::env::var("ASAHI_NVRAM_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(match verbosity {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        });
+    // only fails if a logger is already installed, which then keeps being used
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -1,6 +1,9 @@
 use std::{
-    io::{Seek, SeekFrom, Write},
-    os::unix::io::AsRawFd,
+    borrow::Cow,
+    fmt::{Display, Formatter},
+    io::{self, Seek, SeekFrom, Write},
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
 };
 
 use crate::NvramWriter;
@@ -9,25 +12,37 @@ impl<T> NvramWriter for T
 where
     T: Seek + Write + AsRawFd,
 {
-    fn erase_if_needed(&mut self, offset: u32, size: usize) {
-        if unsafe { mtd_mem_get_info(self.as_raw_fd(), &mut MtdInfoUser::default()) }.is_err() {
-            return;
-        }
-        let erase_info = EraseInfoUser {
-            start: offset,
-            length: size as u32,
-        };
-        unsafe {
-            mtd_mem_erase(self.as_raw_fd(), &erase_info).unwrap();
+    fn erase_if_needed(&mut self, offset: u32, size: usize) -> io::Result<()> {
+        match nvram_info(self.as_raw_fd()) {
+            Some(info) => erase(self.as_raw_fd(), &info, offset, size),
+            None => Ok(()),
         }
     }
 
     fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        let buf = match nvram_info(self.as_raw_fd()) {
+            Some(info) => align_write(offset, buf, info.writesize, info.size)?,
+            None => Cow::Borrowed(buf),
+        };
         self.seek(SeekFrom::Start(offset as u64))?;
-        self.write_all(buf)?;
+        self.write_all(&buf)?;
 
         Ok(())
     }
+
+    // through the fd, T isn't required to be Read
+    fn read_back(&mut self, offset: u32, size: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; size];
+        let mut done = 0;
+        while done < size {
+            let pos = (offset as usize + done) as i64;
+            match nix::sys::uio::pread(self.as_raw_fd(), &mut buf[done..], pos)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => done += n,
+            }
+        }
+        Ok(Some(buf))
+    }
 }
 
 #[repr(C)]
@@ -37,16 +52,277 @@ pub struct EraseInfoUser {
 }
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, Debug, Clone)]
 pub struct MtdInfoUser {
-    ty: u8,
-    flags: u32,
-    size: u32,
-    erasesize: u32,
-    writesize: u32,
-    oobsize: u32,
+    pub ty: u8,
+    pub flags: u32,
+    pub size: u32,
+    pub erasesize: u32,
+    pub writesize: u32,
+    pub oobsize: u32,
     padding: u64,
 }
 
+// None if fd isn't an mtd device, e.g. a file holding an nvram dump
+pub fn nvram_info(fd: RawFd) -> Option<MtdInfoUser> {
+    let mut info = MtdInfoUser::default();
+    unsafe { mtd_mem_get_info(fd, &mut info) }.ok()?;
+    Some(info)
+}
+
+// the kernel refuses to open the read-only /dev/mtdNro nodes for writing. for such a node, this
+// is the writable node of the same device, so tools can point there up front instead of failing
+// with a permission error. None for every other path, symlinks like /dev/mtd/by-name are followed.
+pub fn writable_node(path: &Path) -> Option<PathBuf> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let name = path.file_name()?.to_str()?;
+    let index = name.strip_prefix("mtd")?.strip_suffix("ro")?;
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(path.with_file_name(format!("mtd{}", index)))
+}
+
+// rounds the range out to whole erase blocks
+fn erase_range(offset: u32, size: u32, erasesize: u32) -> (u32, u32) {
+    if erasesize == 0 {
+        return (offset, size);
+    }
+    let start = offset / erasesize * erasesize;
+    let end = (offset + size).div_ceil(erasesize) * erasesize;
+    (start, end - start)
+}
+
+// flash is programmed in whole pages of writesize bytes. the write is padded with 0xFF, which
+// leaves the flash as it is, to end on a page boundary. the start can't be moved back the same
+// way without knowing what is stored before it, so it has to be aligned already.
+fn align_write(
+    offset: u32,
+    buf: &[u8],
+    writesize: u32,
+    device_size: u32,
+) -> io::Result<Cow<'_, [u8]>> {
+    let writesize = writesize as usize;
+    if writesize <= 1 {
+        return Ok(Cow::Borrowed(buf));
+    }
+    let (start, len) = (offset as usize, buf.len());
+    let end = (start + len).next_multiple_of(writesize);
+    if !start.is_multiple_of(writesize) || end > device_size as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "writing {:#x}..{:#x} doesn't fit the {:#x} byte pages of a {:#x} byte device",
+                start,
+                start + len,
+                writesize,
+                device_size
+            ),
+        ));
+    }
+    if end == start + len {
+        return Ok(Cow::Borrowed(buf));
+    }
+    let mut padded = buf.to_vec();
+    padded.resize(end - start, 0xFF);
+    Ok(Cow::Owned(padded))
+}
+
+fn erase(fd: RawFd, info: &MtdInfoUser, offset: u32, size: usize) -> io::Result<()> {
+    let (start, length) = erase_range(offset, size as u32, info.erasesize);
+    // the caller only rewrites offset..offset + size, so anything else the erase would cover,
+    // like the other bank, would be lost
+    if start != offset || length != size as u32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "erasing {:#x}..{:#x} needs erasing {:#x}..{:#x} with {:#x} byte erase blocks",
+                offset,
+                offset as usize + size,
+                start,
+                start + length,
+                info.erasesize
+            ),
+        ));
+    }
+    let erase_info = EraseInfoUser { start, length };
+    unsafe { mtd_mem_erase(fd, &erase_info) }?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LayoutWarning {
+    // the image read doesn't cover the whole device
+    SizeMismatch { image: usize, device: usize },
+    NotEraseAligned { image: usize, erasesize: usize },
+    // erasing one bank would also erase (part of) another one
+    BankSmallerThanEraseBlock { bank: usize, erasesize: usize },
+    // banks don't start on a page boundary, so they can't be written
+    BankNotWriteAligned { bank: usize, writesize: usize },
+}
+
+impl Display for LayoutWarning {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            LayoutWarning::SizeMismatch { image, device } => write!(
+                f,
+                "nvram image is {:#x} bytes but the device is {:#x} bytes",
+                image, device
+            ),
+            LayoutWarning::NotEraseAligned { image, erasesize } => write!(
+                f,
+                "nvram image size {:#x} is not a multiple of the erase block size {:#x}",
+                image, erasesize
+            ),
+            LayoutWarning::BankSmallerThanEraseBlock { bank, erasesize } => write!(
+                f,
+                "nvram banks of {:#x} bytes can't be erased separately with {:#x} byte erase blocks",
+                bank, erasesize
+            ),
+            LayoutWarning::BankNotWriteAligned { bank, writesize } => write!(
+                f,
+                "nvram banks of {:#x} bytes don't start on the device's {:#x} byte write pages",
+                bank, writesize
+            ),
+        }
+    }
+}
+
+// checks the assumptions the parsers make about the device, writing to a device that fails
+// them can destroy more than the bank being written
+pub fn check_layout(info: &MtdInfoUser, image_len: usize, bank_size: usize) -> Vec<LayoutWarning> {
+    let device = info.size as usize;
+    let erasesize = info.erasesize as usize;
+    let writesize = info.writesize as usize;
+    let mut warnings = Vec::new();
+    if image_len != device {
+        warnings.push(LayoutWarning::SizeMismatch {
+            image: image_len,
+            device,
+        });
+    }
+    if writesize > 1 && !bank_size.is_multiple_of(writesize) {
+        warnings.push(LayoutWarning::BankNotWriteAligned {
+            bank: bank_size,
+            writesize,
+        });
+    }
+    if erasesize == 0 {
+        return warnings;
+    }
+    if !image_len.is_multiple_of(erasesize) {
+        warnings.push(LayoutWarning::NotEraseAligned {
+            image: image_len,
+            erasesize,
+        });
+    }
+    if !bank_size.is_multiple_of(erasesize) {
+        warnings.push(LayoutWarning::BankSmallerThanEraseBlock {
+            bank: bank_size,
+            erasesize,
+        });
+    }
+    warnings
+}
+
 nix::ioctl_write_ptr!(mtd_mem_erase, b'M', 2, EraseInfoUser);
 nix::ioctl_read!(mtd_mem_get_info, b'M', 1, MtdInfoUser);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_erase_range() {
+        assert_eq!(erase_range(0x10000, 0x10000, 0x1000), (0x10000, 0x10000));
+        assert_eq!(erase_range(0x10000, 0x10000, 0x20000), (0, 0x20000));
+        assert_eq!(erase_range(0, 0x7FE0, 0x1000), (0, 0x8000));
+        assert_eq!(erase_range(0x1800, 0x1000, 0x1000), (0x1000, 0x2000));
+        assert_eq!(erase_range(0x1800, 0x1000, 0), (0x1800, 0x1000));
+    }
+
+    #[test]
+    fn test_writable_node() {
+        let dir = std::env::temp_dir().join(format!("apple-nvram-mtd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+        for name in ["mtd0", "mtd12ro", "mtdro", "nvramro"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        std::os::unix::fs::symlink(dir.join("mtd12ro"), dir.join("nvram")).unwrap();
+        assert_eq!(writable_node(&dir.join("mtd12ro")), Some(dir.join("mtd12")));
+        assert_eq!(writable_node(&dir.join("nvram")), Some(dir.join("mtd12")));
+        assert_eq!(writable_node(&dir.join("mtd0")), None);
+        assert_eq!(writable_node(&dir.join("mtdro")), None);
+        assert_eq!(writable_node(&dir.join("nvramro")), None);
+        assert_eq!(writable_node(&dir.join("missing")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_back_file() {
+        let path =
+            std::env::temp_dir().join(format!("apple-nvram-read-back-{}", std::process::id()));
+        std::fs::write(&path, [0x11, 0x22, 0x33, 0x44]).unwrap();
+        let mut file = std::fs::File::open(&path).unwrap();
+        assert_eq!(file.read_back(1, 2).unwrap(), Some(vec![0x22, 0x33]));
+        assert!(file.read_back(3, 2).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_align_write() {
+        let buf = [0x12; 0x10];
+        assert_eq!(align_write(0x100, &buf, 0x10, 0x1000).unwrap(), &buf[..]);
+        assert_eq!(align_write(0x104, &buf, 0, 0x1000).unwrap(), &buf[..]);
+        assert_eq!(align_write(0x104, &buf, 1, 0x1000).unwrap(), &buf[..]);
+        let padded = align_write(0x100, &buf, 0x100, 0x1000).unwrap();
+        assert_eq!(padded.len(), 0x100);
+        assert_eq!(padded[..0x10], buf);
+        assert!(padded[0x10..].iter().all(|&b| b == 0xFF));
+        // the start can't be padded, and the padding can't run past the device
+        assert!(align_write(0x108, &buf, 0x10, 0x1000).is_err());
+        assert!(align_write(0xF00, &buf, 0x200, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let info = |size, erasesize| MtdInfoUser {
+            size,
+            erasesize,
+            ..Default::default()
+        };
+        assert_eq!(check_layout(&info(0x20000, 0x4000), 0x20000, 0x10000), []);
+        let pages = MtdInfoUser {
+            writesize: 0x3000,
+            ..info(0x20000, 0x1000)
+        };
+        assert_eq!(
+            check_layout(&pages, 0x20000, 0x10000),
+            [LayoutWarning::BankNotWriteAligned {
+                bank: 0x10000,
+                writesize: 0x3000
+            }]
+        );
+        assert_eq!(
+            check_layout(&info(0x100000, 0x20000), 0x100000, 0x10000),
+            [LayoutWarning::BankSmallerThanEraseBlock {
+                bank: 0x10000,
+                erasesize: 0x20000
+            }]
+        );
+        assert_eq!(
+            check_layout(&info(0x20000, 0x8000), 0x1c000, 0x10000),
+            [
+                LayoutWarning::SizeMismatch {
+                    image: 0x1c000,
+                    device: 0x20000
+                },
+                LayoutWarning::NotEraseAligned {
+                    image: 0x1c000,
+                    erasesize: 0x8000
+                }
+            ]
+        );
+    }
+}
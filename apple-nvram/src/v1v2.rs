@@ -1,10 +1,20 @@
-use std::{
-    borrow::Cow,
-    collections::HashMap,
+use alloc::{borrow::Cow, boxed::Box, string::String, vec::Vec};
+use core::{
     fmt::{Debug, Display, Formatter},
+    iter::Peekable,
 };
 
-use crate::{chrp_checksum_add, slice_find, slice_rstrip, Error, Result, VarType};
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as Map;
+#[cfg(feature = "std")]
+use std::collections::HashMap as Map;
+
+use crate::{
+    chrp_checksum_add, slice_find, slice_rstrip, variable_line, ChecksumKind, Error, OwnedVariable,
+    Result, VarType, DISPLAY_VALUE_LIMIT,
+};
+
+const PARTITION_SIZE: usize = 0x10000;
 
 pub struct UnescapeVal<I> {
     inner: I,
@@ -49,6 +59,47 @@ where
     }
 }
 
+// encodes runs of 0x00 and 0xFF the way UnescapeVal expects them
+pub struct EscapeVal<I: Iterator<Item = u8>> {
+    inner: Peekable<I>,
+    pending: Option<u8>,
+}
+
+impl<I> EscapeVal<I>
+where
+    I: Iterator<Item = u8>,
+{
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner: inner.peekable(),
+            pending: None,
+        }
+    }
+}
+
+impl<I> Iterator for EscapeVal<I>
+where
+    I: Iterator<Item = u8>,
+{
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        if let Some(count) = self.pending.take() {
+            return Some(count);
+        }
+        let n = self.inner.next()?;
+        if n != 0 && n != 0xFF {
+            return Some(n);
+        }
+        let mut count = 1;
+        while count < 0x7F && self.inner.peek() == Some(&n) {
+            self.inner.next();
+            count += 1;
+        }
+        self.pending = Some(if n == 0 { count } else { count | 0x80 });
+        Some(0xFF)
+    }
+}
+
 #[derive(Clone)]
 pub struct CHRPHeader<'a> {
     pub name: &'a [u8],
@@ -57,7 +108,7 @@ pub struct CHRPHeader<'a> {
 }
 
 impl Debug for CHRPHeader<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("CHRPHeader")
             .field("name", &String::from_utf8_lossy(self.name).into_owned())
             .field("size", &self.size)
@@ -77,6 +128,7 @@ impl CHRPHeader<'_> {
             size,
             signature,
         };
+        // this is also how a header is recognized at all, so it stays a parse error
         if cand.checksum() != cksum {
             return Err(Error::ParseError);
         }
@@ -110,40 +162,69 @@ pub struct Variable<'a> {
     pub typ: VarType,
 }
 
+impl Variable<'_> {
+    pub fn to_owned_variable(&self) -> OwnedVariable {
+        OwnedVariable {
+            partition: self.typ,
+            key: self.key.to_vec(),
+            value: UnescapeVal::new(self.value.iter().copied()).collect(),
+            guid: self.typ.guid(),
+            attrs: None,
+            state: None,
+        }
+    }
+}
+
 impl<'a> crate::Variable<'a> for Variable<'a> {
     fn value(&self) -> Cow<'a, [u8]> {
         Cow::Owned(UnescapeVal::new(self.value.iter().copied()).collect())
     }
+
+    fn value_bytes(&self) -> Box<dyn Iterator<Item = u8> + '_> {
+        Box::new(UnescapeVal::new(self.value.iter().copied()))
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn typ(&self) -> VarType {
+        self.typ
+    }
 }
 
 impl Display for Variable<'_> {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let key = String::from_utf8_lossy(&self.key);
-        let mut value = String::new();
-        for c in UnescapeVal::new(self.value.iter().copied()) {
-            if (c as char).is_ascii() && !(c as char).is_ascii_control() {
-                value.push(c as char);
-            } else {
-                value.push_str(&format!("%{c:02x}"));
-            }
-        }
-
-        let value: String = value.chars().take(128).collect();
-        write!(f, "{}:{}={}", self.typ, key, value)
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        let value = UnescapeVal::new(self.value.iter().copied());
+        let line = variable_line(
+            &self.typ.guid(),
+            &self.key,
+            value,
+            Some(DISPLAY_VALUE_LIMIT),
+        );
+        write!(f, "{}", line)
     }
 }
 
 #[derive(Clone)]
 pub struct Section<'a> {
     pub header: CHRPHeader<'a>,
-    pub values: HashMap<Cow<'a, [u8]>, Variable<'a>>,
+    pub values: Map<Cow<'a, [u8]>, Variable<'a>>,
 }
 
 impl Section<'_> {
     pub fn parse(mut nvr: &[u8]) -> Result<Section<'_>> {
+        if nvr.len() < 16 {
+            return Err(Error::ParseError);
+        }
         let header = CHRPHeader::parse(&nvr[..16])?;
-        nvr = &nvr[16..];
-        let mut values = HashMap::new();
+        // the size includes the header and comes from the image, it can't be trusted
+        let size = header.size as usize * 16;
+        if size < 16 || size > nvr.len() {
+            return Err(Error::ParseError);
+        }
+        nvr = &nvr[16..size];
+        let mut values = Map::new();
         loop {
             let zero = slice_find(nvr, &0);
             if zero.is_none() {
@@ -177,6 +258,15 @@ impl Section<'_> {
     fn size_bytes(&self) -> usize {
         self.header.size as usize * 16
     }
+    fn used_bytes(&self) -> usize {
+        16 + self
+            .values
+            .values()
+            .fold(0, |acc, v| acc + v.key.len() + v.value.len() + 2)
+    }
+    fn free_bytes(&self) -> usize {
+        self.size_bytes().saturating_sub(self.used_bytes())
+    }
     pub fn serialize(&self, v: &mut Vec<u8>) -> Result<()> {
         let start_size = v.len();
         self.header.serialize(v);
@@ -199,7 +289,7 @@ impl Section<'_> {
 
 struct SectionDebug<'a, 'b>(&'a Section<'b>);
 impl Debug for SectionDebug<'_, '_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         let mut m = f.debug_map();
         for v in self.0.values.values() {
             m.entry(
@@ -215,7 +305,7 @@ impl Debug for SectionDebug<'_, '_> {
 }
 
 impl Debug for Section<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Section")
             .field("header", &self.header)
             .field("values", &SectionDebug(self))
@@ -241,10 +331,16 @@ impl<'a> Partition<'a> {
         let generation = u32::from_le_bytes(nvr[20..24].try_into().unwrap());
         let sec1 = Section::parse(&nvr[32..])?;
         let sec2 = Section::parse(&nvr[(32 + sec1.size_bytes())..])?;
-        let calc_adler =
-            adler32::adler32(&nvr[20..(32 + sec1.size_bytes() + sec2.size_bytes())]).unwrap();
+        let calc_adler = adler32::RollingAdler32::from_buffer(
+            &nvr[20..(32 + sec1.size_bytes() + sec2.size_bytes())],
+        )
+        .hash();
         if adler != calc_adler {
-            return Err(Error::ParseError);
+            return Err(Error::ChecksumMismatch {
+                kind: ChecksumKind::Adler32,
+                expected: adler,
+                actual: calc_adler,
+            });
         }
         let mut com = None;
         let mut sys = None;
@@ -280,7 +376,7 @@ impl<'a> Partition<'a> {
         self.common.serialize(v)?;
         self.system.serialize(v)?;
         let adler_end = v.len();
-        let adler = adler32::adler32(&v[adler_start..adler_end]).unwrap();
+        let adler = adler32::RollingAdler32::from_buffer(&v[adler_start..adler_end]).hash();
         v[(adler_start - 4)..adler_start].copy_from_slice(&adler.to_le_bytes());
         Ok(())
     }
@@ -310,6 +406,12 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
     }
 
     fn insert_variable(&mut self, key: &[u8], value: Cow<'a, [u8]>, typ: VarType) {
+        // values are stored in their on-disk escaped form
+        let value = if value.iter().any(|&c| c == 0 || c == 0xFF) {
+            Cow::Owned(EscapeVal::new(value.iter().copied()).collect())
+        } else {
+            value
+        };
         match typ {
             VarType::Common => &mut self.common,
             VarType::System => &mut self.system,
@@ -334,13 +436,79 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
         .remove(key);
     }
 
+    fn get_variable_by_guid(
+        &self,
+        key: &[u8],
+        guid: &[u8; 16],
+    ) -> Option<&dyn crate::Variable<'a>> {
+        self.get_variable(key, VarType::from_guid(guid)?)
+    }
+
+    fn insert_variable_by_guid(
+        &mut self,
+        key: &[u8],
+        value: Cow<'a, [u8]>,
+        guid: &[u8; 16],
+    ) -> crate::Result<()> {
+        let typ = VarType::from_guid(guid).ok_or(Error::UnsupportedGuid)?;
+        self.insert_variable(key, value, typ);
+        Ok(())
+    }
+
+    fn remove_variable_by_guid(&mut self, key: &[u8], guid: &[u8; 16]) {
+        if let Some(typ) = VarType::from_guid(guid) {
+            self.remove_variable(key, typ);
+        }
+    }
+
     fn variables(&self) -> Box<dyn Iterator<Item = &dyn crate::Variable<'a>> + '_> {
         Box::new(self.variables().map(|e| e as &dyn crate::Variable<'a>))
     }
+
+    fn free_bytes(&self, typ: VarType) -> usize {
+        match typ {
+            VarType::Common => self.common.free_bytes(),
+            VarType::System => self.system.free_bytes(),
+        }
+    }
+
+    fn free_bytes_total(&self) -> usize {
+        self.common.free_bytes() + self.system.free_bytes()
+    }
+
+    fn variable_size(&self, key: &[u8], value: &[u8]) -> usize {
+        let value_len = if value.iter().any(|&c| c == 0 || c == 0xFF) {
+            EscapeVal::new(value.iter().copied()).count()
+        } else {
+            value.len()
+        };
+        // key=value\0
+        key.len() + value_len + 2
+    }
+
+    // the header checksums and the adler32 aren't stored, serialize always computes them
+    fn verify_checksums(&self) -> Vec<&dyn crate::Variable<'a>> {
+        Vec::new()
+    }
+
+    fn recompute_checksums(&mut self) {}
+
+    // sections are rewritten as a whole, so no old copies are kept
+    fn stale_variables(&self) -> Vec<OwnedVariable> {
+        Vec::new()
+    }
+
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn history(&self) -> Vec<OwnedVariable> {
+        self.variables().map(|v| v.to_owned_variable()).collect()
+    }
 }
 
 impl Display for Partition<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "size: {}, generation: {}, count: {}",
@@ -351,34 +519,103 @@ impl Display for Partition<'_> {
     }
 }
 
+// generation of a bank that has a valid header but may fail to parse otherwise
+fn raw_generation(nvr: &[u8]) -> Option<u32> {
+    let header = CHRPHeader::parse(&nvr[..16]).ok()?;
+    if header.name != b"nvram" {
+        return None;
+    }
+    Some(u32::from_le_bytes(nvr[20..24].try_into().unwrap()))
+}
+
 #[derive(Debug)]
 pub struct Nvram<'a> {
     pub partitions: [Partition<'a>; 2],
     pub active: usize,
+    // a bank that failed to parse although its header claims a newer generation
+    pub newer_invalid: Option<usize>,
 }
 
 impl<'a> Nvram<'a> {
     pub fn parse(nvr: &[u8]) -> Result<Nvram<'_>> {
+        if nvr.len() < 2 * PARTITION_SIZE {
+            return Err(Error::ParseError);
+        }
         let p1;
         let p2;
-        match (Partition::parse(nvr), Partition::parse(&nvr[0x10000..])) {
+        let mut newer_invalid = None;
+        match (
+            Partition::parse(nvr),
+            Partition::parse(&nvr[PARTITION_SIZE..]),
+        ) {
             (Err(err), Err(_)) => return Err(err),
             (Ok(p1r), Err(_)) => {
                 p1 = p1r;
                 p2 = p1.clone();
+                if raw_generation(&nvr[PARTITION_SIZE..])
+                    .is_some_and(|g| crate::generation_newer(g, p1.generation))
+                {
+                    newer_invalid = Some(1);
+                }
             }
             (Err(_), Ok(p2r)) => {
                 p2 = p2r;
                 p1 = p2.clone();
+                if raw_generation(nvr).is_some_and(|g| crate::generation_newer(g, p2.generation)) {
+                    newer_invalid = Some(0);
+                }
             }
             (Ok(p1r), Ok(p2r)) => {
                 p1 = p1r;
                 p2 = p2r;
             }
         }
-        let active = if p1.generation > p2.generation { 0 } else { 1 };
+        // on equal generations there is no telling which bank was written last, bank 1 is used
+        // as it always has been. bank_warnings reports it when their contents differ.
+        let active = if crate::generation_newer(p1.generation, p2.generation) {
+            0
+        } else {
+            1
+        };
         let partitions = [p1, p2];
-        Ok(Nvram { partitions, active })
+        Ok(Nvram {
+            partitions,
+            active,
+            newer_invalid,
+        })
+    }
+
+    // an image with empty common and system sections in both banks. sizes are in bytes
+    // including the section headers, and are rounded down to 16 byte blocks.
+    pub fn empty(system_size: u32, common_size: u32) -> Result<Vec<u8>> {
+        let (system_size, common_size) = (system_size / 16, common_size / 16);
+        if 32 + (system_size + common_size) as usize * 16 > PARTITION_SIZE {
+            return Err(Error::SectionTooBig);
+        }
+        let section = |name, size| Section {
+            header: CHRPHeader {
+                name,
+                size: size as u16,
+                signature: 0x70,
+            },
+            values: Default::default(),
+        };
+        let part = Partition {
+            header: CHRPHeader {
+                name: b"nvram",
+                size: (PARTITION_SIZE / 16) as u16,
+                signature: 0x5A,
+            },
+            generation: 1,
+            common: section(b"common", common_size),
+            system: section(b"system", system_size),
+        };
+        let mut data = Vec::with_capacity(PARTITION_SIZE * 2);
+        for end in [PARTITION_SIZE, PARTITION_SIZE * 2] {
+            part.serialize(&mut data)?;
+            data.resize(end, 0xFF);
+        }
+        Ok(data)
     }
 
     pub fn partitions(&self) -> impl Iterator<Item = &Partition<'a>> {
@@ -393,10 +630,19 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
         self.partitions[1].serialize(&mut v)?;
         Ok(v)
     }
+    fn to_owned_snapshot(&self) -> Vec<OwnedVariable> {
+        self.partitions[self.active]
+            .variables()
+            .map(|v| v.to_owned_variable())
+            .collect()
+    }
+
     fn prepare_for_write(&mut self) {
+        // calling this twice would overwrite the untouched copy with the edited one
         let inactive = 1 - self.active;
         self.partitions[inactive] = self.partitions[self.active].clone();
-        self.partitions[inactive].generation += 1;
+        self.partitions[inactive].generation =
+            self.partitions[self.active].generation.wrapping_add(1);
         self.active = inactive;
     }
     // fn active_part(&self) -> &Partition<'a> {
@@ -410,10 +656,190 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
         Box::new(self.partitions().map(|e| e as &dyn crate::Partition<'a>))
     }
 
+    fn banks(&self) -> Box<dyn Iterator<Item = crate::Bank<'_, 'a>> + '_> {
+        Box::new(self.partitions().map(|e| crate::Bank::Valid(e)))
+    }
+
+    fn active_bank(&self) -> usize {
+        self.active
+    }
+
+    fn set_active(&mut self, index: usize) -> Result<()> {
+        if index >= self.partitions.len() {
+            return Err(Error::InvalidBank);
+        }
+        self.active = index;
+        Ok(())
+    }
+
+    fn bank_size(&self) -> usize {
+        PARTITION_SIZE
+    }
+
+    fn newer_invalid_bank(&self) -> Option<usize> {
+        self.newer_invalid
+    }
+
+    fn would_switch_bank(&self) -> bool {
+        true
+    }
+
+    #[cfg(feature = "std")]
     fn apply(&mut self, w: &mut dyn crate::NvramWriter) -> Result<()> {
         let data = self.serialize()?;
-        w.erase_if_needed(0, data.len());
-        w.write_all(0, &data).map_err(|e| Error::ApplyError(e))?;
+        log::debug!(
+            "writing {} bytes, bank {} is now active",
+            data.len(),
+            self.active
+        );
+        w.erase_if_needed(0, data.len())
+            .map_err(Error::EraseError)?;
+        w.write_all(0, &data).map_err(Error::ApplyError)?;
+        Ok(())
+    }
+
+    // apply already rewrites everything in place
+    #[cfg(feature = "std")]
+    fn apply_in_place(&mut self, w: &mut dyn crate::NvramWriter) -> Result<()> {
+        self.apply(w)
+    }
+
+    // both banks are always written
+    #[cfg(feature = "std")]
+    fn repair_generations(&mut self, w: &mut dyn crate::NvramWriter) -> Result<()> {
+        let generations = self.partitions.each_ref().map(|p| Some(p.generation));
+        let renumbered = crate::renumbered_generations(&generations, self.active);
+        for (p, g) in self.partitions.iter_mut().zip(renumbered) {
+            p.generation = g.unwrap();
+        }
+        self.apply(w)
+    }
+
+    fn store_header_flags(&self) -> Option<(u8, u8)> {
+        None
+    }
+
+    #[cfg(feature = "std")]
+    fn set_store_header_flags(
+        &mut self,
+        _w: &mut dyn crate::NvramWriter,
+        _state: u8,
+        _flags: u8,
+    ) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Partition as PartitionT, Variable as VariableT};
+    use alloc::format;
+
+    fn section(name: &'static [u8], size: u16) -> Section<'static> {
+        Section {
+            header: CHRPHeader {
+                name,
+                size,
+                signature: 0x70,
+            },
+            values: Map::new(),
+        }
+    }
+
+    fn empty_partition() -> Partition<'static> {
+        Partition {
+            header: CHRPHeader {
+                name: b"nvram",
+                size: 0x1000,
+                signature: 0x5A,
+            },
+            generation: 1,
+            common: section(b"common", 0x800),
+            system: section(b"system", 0x7FE),
+        }
+    }
+
+    #[test]
+    fn test_empty() -> Result<()> {
+        let data = Nvram::empty(0x1000, 0x2008)?;
+        let nv = Nvram::parse(&data)?;
+        let part = &nv.partitions[nv.active];
+        assert_eq!(part.system.size_bytes(), 0x1000);
+        assert_eq!(part.common.size_bytes(), 0x2000);
+        assert!(part.variables().next().is_none());
+        assert!(matches!(
+            Nvram::empty(0x8000, 0x8000),
+            Err(Error::SectionTooBig)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_section_sizes_are_rejected() {
+        let data = Nvram::empty(0x7FE0, 0x8000).unwrap();
+        for size in [0xFFFF, 0] {
+            let mut bad = data.clone();
+            let mut header = Vec::new();
+            CHRPHeader {
+                name: b"common",
+                size,
+                signature: 0x70,
+            }
+            .serialize(&mut header);
+            // the common section comes first, in both banks
+            for bank in [0, PARTITION_SIZE] {
+                bad[bank + 32..bank + 48].copy_from_slice(&header);
+            }
+            assert!(matches!(
+                crate::nvram_parse(&bad),
+                Err(crate::Error::ParseError | crate::Error::UnknownFormat { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_value_bytes_unescaped() {
+        let var = Variable {
+            key: Cow::Borrowed(b"test-variable"),
+            value: Cow::Borrowed(&[b'a', 0xFF, 0x03, b'b', 0xFF, 0x82]),
+            typ: VarType::System,
+        };
+
+        let expected = [b'a', 0, 0, 0, b'b', 0xFF, 0xFF];
+        assert_eq!(var.value_len(), expected.len());
+        assert!(var.value_eq(&expected));
+        assert!(!var.value_eq(&expected[..6]));
+        assert!(var.value_bytes().eq(var.value().iter().copied()));
+    }
+
+    #[test]
+    fn test_escape_round_trip() -> Result<()> {
+        let values: [&[u8]; 4] = [
+            b"embedded\0nul",
+            &[0xFF; 200],
+            &[0, 0, 0xFF, 0xFF, 0xFF, b'x', 0],
+            b"plain",
+        ];
+        let mut part = empty_partition();
+        for (i, value) in values.iter().enumerate() {
+            part.insert_variable(
+                format!("test-{i}").as_bytes(),
+                Cow::Borrowed(value),
+                VarType::System,
+            );
+        }
+
+        let mut data = Vec::new();
+        part.serialize(&mut data)?;
+        let part_after = Partition::parse(&data)?;
+        for (i, value) in values.iter().enumerate() {
+            let var = part_after
+                .get_variable(format!("test-{i}").as_bytes(), VarType::System)
+                .unwrap();
+            assert_eq!(var.value(), Cow::Borrowed(*value));
+        }
+
         Ok(())
     }
 }
@@ -1,10 +1,16 @@
-use std::{
-    borrow::Cow,
+// bank switching is only used by apply(), which needs std
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+use alloc::{borrow::Cow, boxed::Box, vec::Vec};
+use core::{
     fmt::{Display, Formatter},
     ops::ControlFlow,
 };
 
-use crate::{Error, VarType};
+use crate::{
+    variable_line, ChecksumKind, Error, OwnedVariable, VarType, APPLE_COMMON_VARIABLE_GUID,
+    APPLE_SYSTEM_VARIABLE_GUID, DISPLAY_VALUE_LIMIT,
+};
 
 // https://github.com/apple-oss-distributions/xnu/blob/main/iokit/Kernel/IONVRAMV3Handler.cpp#L630
 
@@ -19,13 +25,6 @@ const VAR_ADDED: u8 = 0x7F;
 const VAR_IN_DELETED_TRANSITION: u8 = 0xFE;
 const VAR_DELETED: u8 = 0xFD;
 
-const APPLE_COMMON_VARIABLE_GUID: &[u8; 16] = &[
-    0x7C, 0x43, 0x61, 0x10, 0xAB, 0x2A, 0x4B, 0xBB, 0xA8, 0x80, 0xFE, 0x41, 0x99, 0x5C, 0x9F, 0x82,
-];
-const APPLE_SYSTEM_VARIABLE_GUID: &[u8; 16] = &[
-    0x40, 0xA0, 0xDD, 0xD2, 0x77, 0xF8, 0x43, 0x92, 0xB4, 0xA3, 0x1E, 0x73, 0x04, 0x20, 0x65, 0x16,
-];
-
 #[derive(Debug, Default)]
 enum Slot<T> {
     Valid(T),
@@ -60,11 +59,37 @@ impl<T> Slot<T> {
     }
 
     pub fn empty(&self) -> bool {
-        match self {
-            Slot::Empty => true,
-            _ => false,
+        matches!(self, Slot::Empty)
+    }
+}
+
+// the store headers are enough to find the newest bank, so only that bank has to be read in full.
+// None if that bank doesn't parse or there is no v3 store at all, the caller then has to read
+// everything to find out what the full parse would pick.
+#[cfg(feature = "std")]
+pub(crate) fn read_active_bank<R: std::io::Read + std::io::Seek>(
+    r: &mut R,
+) -> std::io::Result<Option<Vec<u8>>> {
+    use std::io::SeekFrom;
+    let len = r.seek(SeekFrom::End(0))? as usize;
+    let mut active = None;
+    let mut header = [0; STORE_HEADER_SIZE];
+    for i in 0..(len / PARTITION_SIZE).min(16) {
+        r.seek(SeekFrom::Start((i * PARTITION_SIZE) as u64))?;
+        r.read_exact(&mut header)?;
+        if let Ok(h) = StoreHeader::parse(&header) {
+            if active.is_none_or(|(_, g)| crate::generation_newer(h.generation, g)) {
+                active = Some((i, h.generation));
+            }
         }
     }
+    let Some((i, _)) = active else {
+        return Ok(None);
+    };
+    let mut bank = vec![0; PARTITION_SIZE];
+    r.seek(SeekFrom::Start((i * PARTITION_SIZE) as u64))?;
+    r.read_exact(&mut bank)?;
+    Ok(Partition::parse(&bank).is_ok().then_some(bank))
 }
 
 #[derive(Debug)]
@@ -72,51 +97,105 @@ pub struct Nvram<'a> {
     partitions: [Slot<Partition<'a>>; 16],
     partition_count: usize,
     active: usize,
+    newer_invalid: Option<usize>,
 }
 
 impl<'a> Nvram<'a> {
-    pub fn parse(nvr: &'a [u8]) -> crate::Result<Nvram<'_>> {
+    pub fn parse(nvr: &'a [u8]) -> crate::Result<Nvram<'a>> {
         let partition_count = nvr.len() / PARTITION_SIZE;
         let mut partitions: [Slot<Partition<'a>>; 16] = Default::default();
-        let mut active = 0;
-        let mut max_gen = 0;
-        let mut valid_partitions = 0;
-
-        for i in 0..partition_count {
+        // on equal generations the first bank is used
+        let mut newest: Option<(usize, u32)> = None;
+        // invalid bank with the highest generation in its store header
+        let mut newest_invalid: Option<(usize, u32)> = None;
+        // reported when no bank is valid
+        let mut first_error = None;
+
+        for (i, slot) in partitions.iter_mut().enumerate().take(partition_count) {
             let offset = i * PARTITION_SIZE;
             if offset >= nvr.len() {
                 break;
             }
             match Partition::parse(&nvr[offset..offset + PARTITION_SIZE]) {
                 Ok(p) => {
-                    let p_gen = p.generation();
-                    if p_gen > max_gen {
-                        active = i;
-                        max_gen = p_gen;
+                    let p_gen = p.header.generation;
+                    if newest.is_none_or(|(_, g)| crate::generation_newer(p_gen, g)) {
+                        newest = Some((i, p_gen));
                     }
-                    partitions[i] = Slot::Valid(p);
-                    valid_partitions += 1;
+                    *slot = Slot::Valid(p);
                 }
                 Err(V3Error::Empty) => {
-                    partitions[i] = Slot::Empty;
+                    *slot = Slot::Empty;
                 }
-                Err(_) => {
-                    partitions[i] = Slot::Invalid;
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    let bank = &nvr[offset..offset + STORE_HEADER_SIZE];
+                    if let Ok(header) = StoreHeader::parse(bank) {
+                        if newest_invalid
+                            .is_none_or(|(_, g)| crate::generation_newer(header.generation, g))
+                        {
+                            newest_invalid = Some((i, header.generation));
+                        }
+                    }
+                    *slot = Slot::Invalid;
                 }
             }
         }
 
-        if valid_partitions == 0 {
-            return Err(Error::ParseError);
-        }
+        let Some((active, max_gen)) = newest else {
+            return Err(first_error.map_or(Error::ParseError, Error::from));
+        };
 
+        let newer_invalid = newest_invalid
+            .filter(|&(_, g)| crate::generation_newer(g, max_gen))
+            .map(|(i, _)| i);
         Ok(Nvram {
             partitions,
             partition_count,
             active,
+            newer_invalid,
         })
     }
 
+    // an image with an empty store in the first bank and the remaining banks erased
+    pub fn empty(bank_count: usize, system_size: u32, common_size: u32) -> Vec<u8> {
+        let header = StoreHeader {
+            name: VARIABLE_STORE_SIGNATURE,
+            size: PARTITION_SIZE as u32,
+            generation: 1,
+            // the values found on real machines
+            state: 0xFE,
+            flags: 0x5A,
+            version: VARIABLE_STORE_VERSION,
+            system_size,
+            common_size,
+        };
+        let mut data = Vec::with_capacity(bank_count * PARTITION_SIZE);
+        header.serialize(&mut data);
+        data.resize(bank_count * PARTITION_SIZE, 0xFF);
+        data
+    }
+
+    // a two bank image holding one variable, written the way a real change would be. lets the
+    // tools test their decoders against the stored form instead of a bare value
+    #[cfg(feature = "std")]
+    pub fn with_variable(typ: VarType, key: &[u8], value: &[u8]) -> crate::Result<Vec<u8>> {
+        let empty = Self::empty(2, 0x4000, 0xC000);
+        let mut w = crate::MemoryWriter::new(empty.clone());
+        let mut nv = Nvram::parse(&empty)?;
+        crate::Nvram::set(&mut nv, &mut w, typ, key, value)?;
+        Ok(w.data)
+    }
+
+    // the active bank's current variables alone in the first bank, the other banks erased. drops
+    // the stale entries, which still hold the values that were replaced
+    pub fn compacted(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.partition_count * PARTITION_SIZE);
+        self.active_part().clone_active().serialize(&mut data);
+        data.resize(self.partition_count * PARTITION_SIZE, 0xFF);
+        data
+    }
+
     fn partitions(&self) -> impl Iterator<Item = &Partition<'a>> {
         self.partitions
             .iter()
@@ -147,18 +226,64 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
         Ok(v)
     }
 
+    fn to_owned_snapshot(&self) -> Vec<OwnedVariable> {
+        self.active_part()
+            .variables()
+            .map(|v| v.to_owned_variable())
+            .collect()
+    }
+
     fn prepare_for_write(&mut self) {
-        // nop
+        // nop, apply decides whether the changes fit in the active bank or need a new one
     }
 
     fn partitions(&self) -> Box<dyn Iterator<Item = &dyn crate::Partition<'a>> + '_> {
         Box::new(self.partitions().map(|p| p as &dyn crate::Partition<'a>))
     }
 
+    fn banks(&self) -> Box<dyn Iterator<Item = crate::Bank<'_, 'a>> + '_> {
+        Box::new(
+            self.partitions
+                .iter()
+                .take(self.partition_count)
+                .map(|x| match x {
+                    Slot::Valid(p) => crate::Bank::Valid(p),
+                    Slot::Invalid => crate::Bank::Invalid,
+                    Slot::Empty => crate::Bank::Empty,
+                }),
+        )
+    }
+
+    fn active_bank(&self) -> usize {
+        self.active
+    }
+
+    fn set_active(&mut self, index: usize) -> crate::Result<()> {
+        if index >= self.partition_count || !matches!(self.partitions[index], Slot::Valid(_)) {
+            return Err(Error::InvalidBank);
+        }
+        self.active = index;
+        Ok(())
+    }
+
+    fn bank_size(&self) -> usize {
+        PARTITION_SIZE
+    }
+
+    fn newer_invalid_bank(&self) -> Option<usize> {
+        self.newer_invalid
+    }
+
+    fn would_switch_bank(&self) -> bool {
+        let ap = self.active_part();
+        ap.total_used() > ap.usable_size()
+    }
+
     fn active_part_mut(&mut self) -> &mut dyn crate::Partition<'a> {
         self.partitions[self.active].as_mut().unwrap()
     }
 
+    #[cfg(feature = "std")]
     fn apply(&mut self, w: &mut dyn crate::NvramWriter) -> crate::Result<()> {
         let ap = self.active_part();
         let offset;
@@ -172,13 +297,19 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
         }
 
         // if total size is too big, copy added variables to the next bank
-        if ap.total_used() <= ap.usable_size() {
+        if !self.would_switch_bank() {
             offset = (self.active * PARTITION_SIZE) as u32;
         } else {
             let new_active = (self.active + 1) % self.partition_count;
+            log::info!(
+                "bank {} is full, moving active variables to bank {}",
+                self.active,
+                new_active
+            );
             offset = (new_active * PARTITION_SIZE) as u32;
             if !self.partitions[new_active].empty() {
-                w.erase_if_needed(offset, PARTITION_SIZE);
+                w.erase_if_needed(offset, PARTITION_SIZE)
+                    .map_err(Error::EraseError)?;
             }
             // must only clone 0x7F variables to the next partition
             self.partitions[new_active] = Slot::Valid(
@@ -196,8 +327,71 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
 
         let mut data = Vec::with_capacity(PARTITION_SIZE);
         self.active_part().serialize(&mut data);
-        w.write_all(offset, &data)
-            .map_err(|e| Error::ApplyError(e))?;
+        log::debug!("writing {} bytes to bank {}", data.len(), self.active);
+        w.write_all(offset, &data).map_err(Error::ApplyError)?;
+        self.partitions[self.active]
+            .as_mut()
+            .unwrap()
+            .mark_written();
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    fn apply_in_place(&mut self, w: &mut dyn crate::NvramWriter) -> crate::Result<()> {
+        let offset = (self.active * PARTITION_SIZE) as u32;
+        let ap = self.partitions[self.active].as_mut().unwrap();
+        if !ap.fold_same_length_updates() {
+            log::debug!("changes don't fit in place, appending them instead");
+            return self.apply(w);
+        }
+        log::debug!("rewriting bank {} in place", self.active);
+        // the old values are overwritten, which flash can only do after an erase
+        ap.rewrite(w, offset)
+    }
+
+    fn store_header_flags(&self) -> Option<(u8, u8)> {
+        let ap = self.active_part();
+        Some((ap.store_state(), ap.store_flags()))
+    }
+
+    #[cfg(feature = "std")]
+    fn set_store_header_flags(
+        &mut self,
+        w: &mut dyn crate::NvramWriter,
+        state: u8,
+        flags: u8,
+    ) -> crate::Result<()> {
+        let offset = (self.active * PARTITION_SIZE) as u32;
+        let ap = self.partitions[self.active].as_mut().unwrap();
+        ap.header.state = state;
+        ap.header.flags = flags;
+        // bits can only be set again after an erase
+        ap.rewrite(w, offset)
+    }
+
+    // empty and invalid banks are left alone, as are banks that already have the right number
+    #[cfg(feature = "std")]
+    fn repair_generations(&mut self, w: &mut dyn crate::NvramWriter) -> crate::Result<()> {
+        let generations: Vec<Option<u32>> = self
+            .partitions
+            .iter()
+            .take(self.partition_count)
+            .map(|s| match s {
+                Slot::Valid(p) => Some(p.header.generation),
+                _ => None,
+            })
+            .collect();
+        let renumbered = crate::renumbered_generations(&generations, self.active);
+        for (i, g) in renumbered.into_iter().enumerate() {
+            let (Some(g), Slot::Valid(p)) = (g, self.partitions[i].as_mut()) else {
+                continue;
+            };
+            if p.header.generation != g {
+                log::debug!("bank {}: generation {} -> {}", i, p.header.generation, g);
+                p.header.generation = g;
+                p.rewrite(w, (i * PARTITION_SIZE) as u32)?;
+            }
+        }
         Ok(())
     }
 }
@@ -207,19 +401,40 @@ pub struct Partition<'a> {
     pub header: StoreHeader<'a>,
     pub values: Vec<Variable<'a>>,
     empty_region_end: usize,
+    // states of the leading entries of values as they are on flash,
+    // entries after them were added since the last write
+    flash_states: Vec<u8>,
 }
 
 #[derive(Debug)]
 enum V3Error {
     ParseError,
+    // a variable's value doesn't match the crc in its header
+    CrcMismatch { expected: u32, actual: u32 },
     Empty,
 }
 
-type Result<T> = std::result::Result<T, V3Error>;
+impl From<V3Error> for Error {
+    fn from(e: V3Error) -> Self {
+        match e {
+            V3Error::CrcMismatch { expected, actual } => Error::ChecksumMismatch {
+                kind: ChecksumKind::Crc32,
+                expected,
+                actual,
+            },
+            V3Error::ParseError | V3Error::Empty => Error::ParseError,
+        }
+    }
+}
+
+type Result<T> = core::result::Result<T, V3Error>;
 
 impl<'a> Partition<'a> {
     fn parse(nvr: &'a [u8]) -> Result<Partition<'a>> {
         if let Ok(header) = StoreHeader::parse(&nvr[..STORE_HEADER_SIZE]) {
+            if header.size() > nvr.len() {
+                return Err(V3Error::ParseError);
+            }
             let mut offset = STORE_HEADER_SIZE;
             let mut values = Vec::new();
             // one byte past the last 0xFF or the end of partition
@@ -264,7 +479,10 @@ impl<'a> Partition<'a> {
 
                 let crc = crc32fast::hash(value);
                 if crc != v_header.crc {
-                    return Err(V3Error::ParseError);
+                    return Err(V3Error::CrcMismatch {
+                        expected: v_header.crc,
+                        actual: crc,
+                    });
                 }
                 let v = Variable {
                     header: v_header,
@@ -278,6 +496,7 @@ impl<'a> Partition<'a> {
 
             Ok(Partition {
                 header,
+                flash_states: values.iter().map(|v| v.header.state).collect(),
                 values,
                 empty_region_end,
             })
@@ -292,34 +511,30 @@ impl<'a> Partition<'a> {
         }
     }
 
-    fn generation(&self) -> u32 {
-        self.header.generation
-    }
-
     fn entries<'b, 'c>(
         &'b mut self,
         key: &'c [u8],
-        typ: VarType,
-    ) -> impl Iterator<Item = &mut Variable<'a>>
+        guid: [u8; 16],
+    ) -> impl Iterator<Item = &'b mut Variable<'a>>
     where
         'a: 'b,
         'c: 'b,
     {
         self.values
             .iter_mut()
-            .filter(move |e| e.key == key && e.typ() == typ)
+            .filter(move |e| e.key == key && e.header.guid == guid)
     }
 
     fn entries_added<'b, 'c>(
         &'b mut self,
         key: &'c [u8],
-        typ: VarType,
-    ) -> impl Iterator<Item = &mut Variable<'a>>
+        guid: [u8; 16],
+    ) -> impl Iterator<Item = &'b mut Variable<'a>>
     where
         'a: 'b,
         'c: 'b,
     {
-        self.entries(key, typ)
+        self.entries(key, guid)
             .filter(|v| v.header.state == VAR_ADDED)
     }
 
@@ -332,7 +547,9 @@ impl<'a> Partition<'a> {
     fn system_used(&self) -> usize {
         self.values
             .iter()
-            .filter(|&v| v.header.state == VAR_ADDED && v.header.guid == APPLE_SYSTEM_VARIABLE_GUID)
+            .filter(|&v| {
+                v.header.state == VAR_ADDED && v.header.guid == *APPLE_SYSTEM_VARIABLE_GUID
+            })
             .fold(0, |acc, v| acc + v.size())
     }
 
@@ -340,7 +557,9 @@ impl<'a> Partition<'a> {
     fn common_used(&self) -> usize {
         self.values
             .iter()
-            .filter(|&v| v.header.state == VAR_ADDED && v.header.guid == APPLE_COMMON_VARIABLE_GUID)
+            .filter(|&v| {
+                v.header.state == VAR_ADDED && v.header.guid == *APPLE_COMMON_VARIABLE_GUID
+            })
             .fold(0, |acc, v| acc + v.size())
     }
 
@@ -360,27 +579,38 @@ impl<'a> Partition<'a> {
 
     fn serialize(&self, v: &mut Vec<u8>) {
         let start_size = v.len();
+        v.reserve(self.header.size());
         self.header.serialize(v);
         // Here we actually want to iterate over all versions of variables so we use the struct field directly.
         for var in &self.values {
             var.serialize(v);
         }
-        let my_size = v.len() - start_size;
-        debug_assert!(v.len() == self.total_used());
+        debug_assert!(v.len() - start_size == self.total_used());
 
         // padding
-        for _ in 0..(self.header.size() - my_size) {
-            v.push(0xFF);
-        }
+        v.resize(start_size + self.header.size(), 0xFF);
     }
 
     fn variables(&self) -> impl Iterator<Item = &Variable<'a>> {
         self.values.iter().filter(|v| v.header.state == VAR_ADDED)
     }
 
+    pub fn store_state(&self) -> u8 {
+        self.header.state
+    }
+
+    pub fn store_flags(&self) -> u8 {
+        self.header.flags
+    }
+
+    // entries that were replaced or deleted but not yet dropped by a bank switch
+    pub fn stale_entries(&self) -> impl Iterator<Item = &Variable<'a>> {
+        self.values.iter().filter(|v| v.header.state != VAR_ADDED)
+    }
+
     fn clone_active(&self) -> Partition<'a> {
         let mut header = self.header.clone();
-        header.generation += 1;
+        header.generation = header.generation.wrapping_add(1);
         Partition {
             header,
             values: self
@@ -395,14 +625,84 @@ impl<'a> Partition<'a> {
                 })
                 .collect(),
             empty_region_end: self.header.size(),
+            flash_states: Vec::new(),
+        }
+    }
+
+    // writes the whole bank again, after an erase as changed bytes may need bits set
+    #[cfg(feature = "std")]
+    fn rewrite(&mut self, w: &mut dyn crate::NvramWriter, offset: u32) -> crate::Result<()> {
+        let mut data = Vec::with_capacity(PARTITION_SIZE);
+        self.serialize(&mut data);
+        w.erase_if_needed(offset, PARTITION_SIZE)
+            .map_err(Error::EraseError)?;
+        w.write_all(offset, &data).map_err(Error::ApplyError)?;
+        self.empty_region_end = self.header.size();
+        self.mark_written();
+        Ok(())
+    }
+
+    fn mark_written(&mut self) {
+        self.flash_states = self.values.iter().map(|v| v.header.state).collect();
+    }
+
+    // if every change since the last write replaced an existing variable with a value of the
+    // same length, copies the new values over the old instances instead of appending them.
+    // returns false and leaves the partition unchanged otherwise.
+    fn fold_same_length_updates(&mut self) -> bool {
+        let written = self.flash_states.len();
+        let (old, new) = self.values.split_at(written);
+        let new: Vec<&Variable> = new.iter().filter(|v| v.header.state == VAR_ADDED).collect();
+        let mut replaced = Vec::new();
+        for (i, var) in old.iter().enumerate() {
+            if self.flash_states[i] != VAR_ADDED || var.header.state == VAR_ADDED {
+                continue;
+            }
+            // removed or overwritten since the last write
+            let Some(update) = new.iter().find(|v| {
+                v.key == var.key
+                    && v.header.guid == var.header.guid
+                    && v.header.data_size == var.header.data_size
+            }) else {
+                return false;
+            };
+            replaced.push((i, update.value.clone(), update.header.crc));
+        }
+        if replaced.is_empty() || replaced.len() != new.len() {
+            return false;
+        }
+        for (i, value, crc) in replaced {
+            let var = &mut self.values[i];
+            var.value = value;
+            var.header.crc = crc;
+            var.header.state = VAR_ADDED;
         }
+        self.values.truncate(written);
+        true
     }
 }
 
 impl<'a> crate::Partition<'a> for Partition<'a> {
     fn get_variable(&self, key: &[u8], typ: VarType) -> Option<&dyn crate::Variable<'a>> {
+        self.get_variable_by_guid(key, &typ.guid())
+    }
+
+    fn insert_variable(&mut self, key: &[u8], value: Cow<'a, [u8]>, typ: VarType) {
+        // apple guids are always supported
+        let _ = self.insert_variable_by_guid(key, value, &typ.guid());
+    }
+
+    fn remove_variable(&mut self, key: &[u8], typ: VarType) {
+        self.remove_variable_by_guid(key, &typ.guid());
+    }
+
+    fn get_variable_by_guid(
+        &self,
+        key: &[u8],
+        guid: &[u8; 16],
+    ) -> Option<&dyn crate::Variable<'a>> {
         self.values.iter().find_map(|e| {
-            if e.key == key && e.typ() == typ && e.header.state == VAR_ADDED {
+            if e.key == key && e.header.guid == *guid && e.header.state == VAR_ADDED {
                 Some(e as &dyn crate::Variable<'a>)
             } else {
                 None
@@ -410,16 +710,18 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
         })
     }
 
-    fn insert_variable(&mut self, key: &[u8], value: Cow<'a, [u8]>, typ: VarType) {
+    fn insert_variable_by_guid(
+        &mut self,
+        key: &[u8],
+        value: Cow<'a, [u8]>,
+        guid: &[u8; 16],
+    ) -> crate::Result<()> {
         // invalidate any previous variable instances
-        for var in self.entries_added(key, typ) {
+        for var in self.entries_added(key, *guid) {
             var.header.state = var.header.state & VAR_DELETED & VAR_IN_DELETED_TRANSITION;
         }
 
-        let guid = match typ {
-            VarType::Common => APPLE_COMMON_VARIABLE_GUID,
-            VarType::System => APPLE_SYSTEM_VARIABLE_GUID,
-        };
+        let guid = *guid;
         let var = Variable {
             header: VarHeader {
                 state: VAR_ADDED,
@@ -433,11 +735,12 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
             value,
         };
         self.values.push(var);
+        Ok(())
     }
 
-    fn remove_variable(&mut self, key: &[u8], typ: VarType) {
+    fn remove_variable_by_guid(&mut self, key: &[u8], guid: &[u8; 16]) {
         // invalidate all previous variable instances
-        for var in self.entries_added(key, typ) {
+        for var in self.entries_added(key, *guid) {
             var.header.state = var.header.state & VAR_DELETED & VAR_IN_DELETED_TRANSITION;
         }
     }
@@ -445,10 +748,58 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
     fn variables(&self) -> Box<dyn Iterator<Item = &dyn crate::Variable<'a>> + '_> {
         Box::new(self.variables().map(|e| e as &dyn crate::Variable<'a>))
     }
+
+    fn free_bytes(&self, typ: VarType) -> usize {
+        match typ {
+            VarType::Common => self.common_size().saturating_sub(self.common_used()),
+            VarType::System => self.system_size().saturating_sub(self.system_used()),
+        }
+    }
+
+    fn free_bytes_total(&self) -> usize {
+        self.usable_size().saturating_sub(self.total_used())
+    }
+
+    fn variable_size(&self, key: &[u8], value: &[u8]) -> usize {
+        // header + key\0 + value
+        VAR_HEADER_SIZE + key.len() + 1 + value.len()
+    }
+
+    fn verify_checksums(&self) -> Vec<&dyn crate::Variable<'a>> {
+        self.values
+            .iter()
+            .filter(|v| v.header.crc != crc32fast::hash(&v.value))
+            .map(|v| v as &dyn crate::Variable<'a>)
+            .collect()
+    }
+
+    fn recompute_checksums(&mut self) {
+        for var in &mut self.values {
+            // the sizes have to match for the variable to be found again
+            var.header.name_size = (var.key.len() + 1) as u32;
+            var.header.data_size = var.value.len() as u32;
+            var.header.crc = crc32fast::hash(&var.value);
+        }
+    }
+
+    fn stale_variables(&self) -> Vec<OwnedVariable> {
+        self.stale_entries()
+            .map(|v| v.to_owned_variable())
+            .collect()
+    }
+
+    fn generation(&self) -> u32 {
+        self.header.generation
+    }
+
+    // entries are only ever appended, so their position is the order they were written in
+    fn history(&self) -> Vec<OwnedVariable> {
+        self.values.iter().map(|v| v.to_owned_variable()).collect()
+    }
 }
 
 impl Display for Partition<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "size: {}, total_used: {}, system_used: {}, common_used: {}, generation: 0x{:02x}, state: 0x{:02x}, flags: 0x{:02x}, count: {}",
@@ -456,7 +807,7 @@ impl Display for Partition<'_> {
             self.total_used(),
             self.system_used(),
             self.common_used(),
-            self.generation(),
+            self.header.generation,
             self.header.state,
             self.header.flags,
             self.values.len()
@@ -525,7 +876,7 @@ impl<'a> StoreHeader<'a> {
 
 #[derive(Debug, Default, Clone)]
 pub struct Variable<'a> {
-    pub header: VarHeader<'a>,
+    pub header: VarHeader,
     pub key: Cow<'a, [u8]>,
     pub value: Cow<'a, [u8]>,
 }
@@ -536,12 +887,23 @@ impl<'a> Variable<'a> {
     }
 
     fn typ(&self) -> VarType {
-        if self.header.guid == APPLE_SYSTEM_VARIABLE_GUID {
+        if self.header.guid == *APPLE_SYSTEM_VARIABLE_GUID {
             return VarType::System;
         }
         VarType::Common
     }
 
+    pub fn to_owned_variable(&self) -> OwnedVariable {
+        OwnedVariable {
+            partition: self.typ(),
+            key: self.key.to_vec(),
+            value: self.value.to_vec(),
+            guid: self.header.guid,
+            attrs: Some(self.header.attrs),
+            state: Some(self.header.state),
+        }
+    }
+
     fn serialize(&self, v: &mut Vec<u8>) {
         self.header.serialize(v);
         v.extend_from_slice(&self.key);
@@ -554,36 +916,53 @@ impl<'a> crate::Variable<'a> for Variable<'a> {
     fn value(&self) -> Cow<'a, [u8]> {
         self.value.clone()
     }
+
+    fn value_bytes(&self) -> Box<dyn Iterator<Item = u8> + '_> {
+        Box::new(self.value.iter().copied())
+    }
+
+    fn value_len(&self) -> usize {
+        self.value.len()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn typ(&self) -> VarType {
+        Variable::typ(self)
+    }
+
+    fn guid(&self) -> [u8; 16] {
+        self.header.guid
+    }
 }
 
 impl Display for Variable<'_> {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let key = String::from_utf8_lossy(&self.key);
-        let mut value = String::new();
-        for c in self.value.iter().copied() {
-            if (c as char).is_ascii() && !(c as char).is_ascii_control() {
-                value.push(c as char);
-            } else {
-                value.push_str(&format!("%{c:02x}"));
-            }
-        }
-
-        write!(f, "{}:{}={}", self.typ(), key, value)
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        let value = self.value.iter().copied();
+        let line = variable_line(
+            &self.header.guid,
+            &self.key,
+            value,
+            Some(DISPLAY_VALUE_LIMIT),
+        );
+        write!(f, "{}", line)
     }
 }
 
 #[derive(Debug, Default, Clone)]
-pub struct VarHeader<'a> {
+pub struct VarHeader {
     pub state: u8,
     pub attrs: u32,
     pub name_size: u32,
     pub data_size: u32,
-    pub guid: &'a [u8],
+    pub guid: [u8; 16],
     pub crc: u32,
 }
 
-impl<'a> VarHeader<'a> {
-    fn parse(nvr: &[u8]) -> Result<VarHeader<'_>> {
+impl VarHeader {
+    fn parse(nvr: &[u8]) -> Result<VarHeader> {
         let start_id = u16::from_le_bytes(nvr[..2].try_into().unwrap());
         if start_id != VARIABLE_DATA {
             return Err(V3Error::ParseError);
@@ -592,10 +971,12 @@ impl<'a> VarHeader<'a> {
         let attrs = u32::from_le_bytes(nvr[4..8].try_into().unwrap());
         let name_size = u32::from_le_bytes(nvr[8..12].try_into().unwrap());
         let data_size = u32::from_le_bytes(nvr[12..16].try_into().unwrap());
-        let guid = &nvr[16..32];
+        let guid = nvr[16..32].try_into().unwrap();
         let crc = u32::from_le_bytes(nvr[32..36].try_into().unwrap());
 
-        if VAR_HEADER_SIZE + (name_size + data_size) as usize > nvr.len() {
+        // the name includes its nul terminator, so it can't be empty. the sizes are added as
+        // usize, a corrupt pair mustn't wrap around to something that fits
+        if name_size == 0 || VAR_HEADER_SIZE + name_size as usize + data_size as usize > nvr.len() {
             return Err(V3Error::ParseError);
         }
 
@@ -616,74 +997,48 @@ impl<'a> VarHeader<'a> {
         v.extend_from_slice(&self.attrs.to_le_bytes());
         v.extend_from_slice(&self.name_size.to_le_bytes());
         v.extend_from_slice(&self.data_size.to_le_bytes());
-        v.extend_from_slice(self.guid);
+        v.extend_from_slice(&self.guid);
         v.extend_from_slice(&self.crc.to_le_bytes());
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
-    use crate::{Nvram as NvramT, NvramWriter, Partition};
-
-    struct TestNvram {
-        data: Vec<u8>,
-        erase_count: usize,
-    }
-
-    impl TestNvram {
-        fn new(data: Vec<u8>) -> TestNvram {
-            Self {
-                data,
-                erase_count: 0,
-            }
-        }
-
-        fn get_data(&self) -> &[u8] {
-            &self.data
-        }
-    }
-
-    impl NvramWriter for TestNvram {
-        fn erase_if_needed(&mut self, offset: u32, size: usize) {
-            for b in self.data.iter_mut().skip(offset as usize).take(size) {
-                *b = 0xFF;
-            }
-            self.erase_count += 1;
-        }
+    use crate::{tests::empty_v3_nvram as empty_nvram, MemoryWriter, Nvram as NvramT, Partition};
 
-        fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
-            for (d, s) in self
-                .data
-                .iter_mut()
-                .skip(offset as usize)
-                .take(buf.len())
-                .zip(buf.iter().copied())
-            {
-                *d &= s;
-            }
-            Ok(())
-        }
-    }
-
-    #[rustfmt::skip]
-    fn store_header() -> &'static [u8] {
-        &[
-            0x33, 0x56, 0x56, 0x4e, 0x00, 0x00, 0x01, 0x00,
-            0x01, 0x00, 0x00, 0x00, 0xfe, 0x5a, 0x01, 0x00,
-            0x00, 0x40, 0x00, 0x00, 0x00, 0xc0, 0x00, 0x00,
-        ]
+    #[test]
+    fn test_with_variable() -> crate::Result<()> {
+        let image = Nvram::with_variable(VarType::System, b"test-variable", b"test-value")?;
+        let nv = Nvram::parse(&image)?;
+        assert_eq!(
+            nv.get(VarType::System, b"test-variable").as_deref(),
+            Some(&b"test-value"[..])
+        );
+        Ok(())
     }
 
-    fn empty_nvram(bank_count: usize) -> Vec<u8> {
-        let mut data = vec![0xFF; PARTITION_SIZE * bank_count];
-        data[0..STORE_HEADER_SIZE].copy_from_slice(store_header());
-        data
+    #[test]
+    fn test_compacted() -> crate::Result<()> {
+        let image = Nvram::with_variable(VarType::System, b"secret", b"old")?;
+        let mut nv = Nvram::parse(&image)?;
+        nv.active_part_mut()
+            .insert_variable(b"secret", Cow::Borrowed(b"new"), VarType::System);
+        let compacted = nv.compacted();
+        assert_eq!(compacted.len(), image.len());
+        let nv = Nvram::parse(&compacted)?;
+        assert_eq!(
+            nv.get(VarType::System, b"secret").as_deref(),
+            Some(&b"new"[..])
+        );
+        assert_eq!(nv.active_part().stale_entries().count(), 0);
+        assert!(!compacted.windows(3).any(|w| w == b"old"));
+        Ok(())
     }
 
     #[test]
     fn test_insert_variable() -> crate::Result<()> {
-        let mut nvr = TestNvram::new(empty_nvram(2));
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
         let data = nvr.get_data().to_owned();
         let mut nv = Nvram::parse(&data)?;
 
@@ -697,6 +1052,7 @@ mod tests {
         );
 
         // write changes
+        assert!(!nv.would_switch_bank());
         nv.apply(&mut nvr)?;
         assert_eq!(nvr.erase_count, 0);
 
@@ -713,7 +1069,7 @@ mod tests {
 
         let test_var_entries: Vec<_> = nv_after
             .active_part_mut()
-            .entries(b"test-variable", VarType::Common)
+            .entries(b"test-variable", VarType::Common.guid())
             .collect();
 
         assert_eq!(test_var_entries.len(), 1);
@@ -743,7 +1099,7 @@ mod tests {
 
         let test_var2_entries: Vec<_> = nv_after2
             .active_part_mut()
-            .entries(b"test-variable", VarType::Common)
+            .entries(b"test-variable", VarType::Common.guid())
             .collect();
 
         assert_eq!(test_var2_entries.len(), 2);
@@ -758,7 +1114,7 @@ mod tests {
 
     #[test]
     fn test_write_to_next_bank() -> crate::Result<()> {
-        let mut nvr = TestNvram::new(empty_nvram(2));
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
         // write something to the second bank to force nvram erase later
         nvr.data[0x10000..0x10007].copy_from_slice(b"garbage");
 
@@ -813,6 +1169,7 @@ mod tests {
         assert_eq!(nv_after.active_part().values.len(), 4);
 
         // write changes
+        assert!(nv_after.would_switch_bank());
         nv_after.apply(&mut nvr)?;
         assert_eq!(nvr.erase_count, 1);
         assert_eq!(nv_after.active, 1);
@@ -861,7 +1218,7 @@ mod tests {
 
     #[test]
     fn test_insert_with_low_space() -> crate::Result<()> {
-        let mut nvr = TestNvram::new(empty_nvram(2));
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
         // this will shrink usable size to 100 bytes
         nvr.data[STORE_HEADER_SIZE + 100] = 0x42;
 
@@ -895,7 +1252,7 @@ mod tests {
 
         let test_var_entries: Vec<_> = nv_after
             .active_part_mut()
-            .entries(b"test-variable", VarType::Common)
+            .entries(b"test-variable", VarType::Common.guid())
             .collect();
 
         assert_eq!(test_var_entries.len(), 1);
@@ -915,4 +1272,293 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_apply_in_place() -> crate::Result<()> {
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"value-1"),
+            VarType::System,
+        );
+        nv.apply_in_place(&mut nvr)?;
+        // nothing to overwrite yet, so this appended
+        assert_eq!(nvr.erase_count, 0);
+
+        for (i, value) in [b"value-2", b"value-3"].iter().enumerate() {
+            nv.active_part_mut().insert_variable(
+                b"test-variable",
+                Cow::Borrowed(*value),
+                VarType::System,
+            );
+            nv.apply_in_place(&mut nvr)?;
+            assert_eq!(nvr.erase_count, i + 1);
+
+            let data_after = nvr.get_data().to_owned();
+            let nv_after = Nvram::parse(&data_after)?;
+            assert_eq!(nv_after.active, 0);
+            assert_eq!(nv_after.active_part().values.len(), 1);
+            let var = nv_after
+                .active_part()
+                .get_variable(b"test-variable", VarType::System)
+                .unwrap();
+            assert_eq!(var.value(), Cow::Borrowed(*value));
+        }
+
+        // a different length can't be written in place
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"longer-value"),
+            VarType::System,
+        );
+        nv.apply_in_place(&mut nvr)?;
+        assert_eq!(nvr.erase_count, 2);
+        let data_after = nvr.get_data().to_owned();
+        let nv_after = Nvram::parse(&data_after)?;
+        assert_eq!(nv_after.active_part().values.len(), 2);
+        let var = nv_after
+            .active_part()
+            .get_variable(b"test-variable", VarType::System)
+            .unwrap();
+        assert_eq!(var.value(), Cow::Borrowed(b"longer-value"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_banks() -> crate::Result<()> {
+        let mut data = empty_nvram(3);
+        data[PARTITION_SIZE * 2..PARTITION_SIZE * 2 + 7].copy_from_slice(b"garbage");
+        let mut nv = Nvram::parse(&data)?;
+
+        let banks: Vec<_> = nv.banks().collect();
+        assert_eq!(banks.len(), 3);
+        assert!(matches!(banks[0], crate::Bank::Valid(_)));
+        assert!(matches!(banks[1], crate::Bank::Empty));
+        assert!(matches!(banks[2], crate::Bank::Invalid));
+        assert_eq!(nv.active_bank(), 0);
+
+        for index in [1, 2, 3] {
+            assert!(matches!(nv.set_active(index), Err(Error::InvalidBank)));
+        }
+        nv.set_active(0)?;
+        assert_eq!(nv.active_bank(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_owned_snapshot() -> crate::Result<()> {
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"old-value"),
+            VarType::System,
+        );
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::System,
+        );
+        nv.apply(&mut nvr)?;
+
+        let data_after = nvr.get_data().to_owned();
+        let nv_after = Nvram::parse(&data_after)?;
+        assert_eq!(
+            nv_after.to_owned_snapshot(),
+            [OwnedVariable {
+                partition: VarType::System,
+                key: b"test-variable".to_vec(),
+                value: b"test-value".to_vec(),
+                guid: *APPLE_SYSTEM_VARIABLE_GUID,
+                attrs: Some(0),
+                state: Some(VAR_ADDED),
+            }]
+        );
+        assert_eq!(
+            nv_after.active_part().stale_variables(),
+            [OwnedVariable {
+                partition: VarType::System,
+                key: b"test-variable".to_vec(),
+                value: b"old-value".to_vec(),
+                guid: *APPLE_SYSTEM_VARIABLE_GUID,
+                attrs: Some(0),
+                state: Some(VAR_ADDED & VAR_DELETED & VAR_IN_DELETED_TRANSITION),
+            }]
+        );
+        let history: Vec<_> = nv_after
+            .active_part()
+            .history()
+            .into_iter()
+            .map(|v| v.value)
+            .collect();
+        assert_eq!(history, [b"old-value".to_vec(), b"test-value".to_vec()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vendor_guid() -> crate::Result<()> {
+        const VENDOR_GUID: [u8; 16] = [
+            0x8B, 0xE4, 0xDF, 0x61, 0x93, 0xCA, 0x11, 0xD2, 0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03,
+            0x2B, 0x8C,
+        ];
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable_by_guid(
+            b"test-variable",
+            Cow::Borrowed(b"vendor-value"),
+            &VENDOR_GUID,
+        )?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"common-value"),
+            VarType::Common,
+        );
+        nv.apply(&mut nvr)?;
+
+        let data_after = nvr.get_data().to_owned();
+        let mut nv_after = Nvram::parse(&data_after)?;
+        let part = nv_after.active_part_mut();
+        let vendor = part
+            .get_variable_by_guid(b"test-variable", &VENDOR_GUID)
+            .unwrap();
+        assert_eq!(vendor.value(), Cow::Borrowed(b"vendor-value"));
+        assert_eq!(vendor.guid(), VENDOR_GUID);
+        assert_eq!(
+            vendor.to_string(),
+            "8BE4DF61-93CA-11D2-AA0D-00E098032B8C:test-variable=vendor-value"
+        );
+        let common = part
+            .get_variable(b"test-variable", VarType::Common)
+            .unwrap();
+        assert_eq!(common.value(), Cow::Borrowed(b"common-value"));
+
+        part.remove_variable_by_guid(b"test-variable", &VENDOR_GUID);
+        assert!(part
+            .get_variable_by_guid(b"test-variable", &VENDOR_GUID)
+            .is_none());
+        assert!(part
+            .get_variable(b"test-variable", VarType::Common)
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_store_header_flags() -> crate::Result<()> {
+        let mut nvr = MemoryWriter::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::Common,
+        );
+        nv.apply(&mut nvr)?;
+        let (state, flags) = nv.store_header_flags().unwrap();
+
+        nv.set_store_header_flags(&mut nvr, !state, !flags)?;
+        assert_eq!(nvr.erase_count, 1);
+        let data_after = nvr.get_data().to_owned();
+        let nv_after = Nvram::parse(&data_after)?;
+        assert_eq!(nv_after.store_header_flags(), Some((!state, !flags)));
+        assert!(nv_after
+            .active_part()
+            .get_variable(b"test-variable", VarType::Common)
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_sizes_are_rejected() -> crate::Result<()> {
+        let mut data = empty_nvram(1);
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"value"),
+            VarType::System,
+        );
+        let mut image = Vec::new();
+        nv.active_part().serialize(&mut image);
+
+        // a store larger than the bank
+        data[4..8].copy_from_slice(&(PARTITION_SIZE as u32 + 1).to_le_bytes());
+        assert!(super::Partition::parse(&data).is_err());
+
+        // a variable without a name, and one whose sizes only fit if they wrap around
+        let name_size = STORE_HEADER_SIZE + 8;
+        let mut bad = image.clone();
+        bad[name_size..name_size + 4].copy_from_slice(&0u32.to_le_bytes());
+        assert!(super::Partition::parse(&bad).is_ok_and(|p| p.values.is_empty()));
+        let mut bad = image.clone();
+        bad[name_size..name_size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        bad[name_size + 4..name_size + 8].copy_from_slice(&2u32.to_le_bytes());
+        assert!(super::Partition::parse(&bad).is_ok_and(|p| p.values.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_active_bank() -> crate::Result<()> {
+        let mut nv_data = empty_nvram(1);
+        let mut nv = Nvram::parse(&nv_data)?;
+        nv.active_part_mut().insert_variable(
+            b"in-bank-1",
+            Cow::Borrowed(b"value"),
+            VarType::System,
+        );
+        let mut bank = Vec::new();
+        nv.active_part().serialize(&mut bank);
+        bank[8..12].copy_from_slice(&2u32.to_le_bytes());
+
+        let mut image = empty_nvram(3);
+        image[PARTITION_SIZE..2 * PARTITION_SIZE].copy_from_slice(&bank);
+        let read = read_active_bank(&mut std::io::Cursor::new(&image)).unwrap();
+        assert_eq!(read.as_deref(), Some(&bank[..]));
+        let nv = Nvram::parse(read.as_ref().unwrap())?;
+        assert!(nv.get(VarType::System, b"in-bank-1").is_some());
+
+        // the newest bank is broken, only a full parse knows which one is used instead
+        image[PARTITION_SIZE + STORE_HEADER_SIZE + VAR_HEADER_SIZE + b"in-bank-1\0".len()] = 0x42;
+        assert!(read_active_bank(&mut std::io::Cursor::new(&image))
+            .unwrap()
+            .is_none());
+        nv_data.fill(0);
+        assert!(read_active_bank(&mut std::io::Cursor::new(&nv_data))
+            .unwrap()
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_recompute_checksums() -> crate::Result<()> {
+        let data = empty_nvram(1);
+        let mut nv = Nvram::parse(&data)?;
+        let part = nv.active_part_mut();
+        part.insert_variable(b"test-variable", Cow::Borrowed(b"value"), VarType::System);
+        assert!(part.verify_checksums().is_empty());
+
+        part.values[0].value = Cow::Borrowed(b"edited-value");
+        let bad: Vec<_> = part.verify_checksums().iter().map(|v| v.key()).collect();
+        assert_eq!(bad, [b"test-variable"]);
+
+        part.recompute_checksums();
+        assert!(part.verify_checksums().is_empty());
+        let mut image = Vec::new();
+        part.serialize(&mut image);
+        let nv_after = Nvram::parse(&image)?;
+        let var = nv_after
+            .active_part()
+            .get_variable(b"test-variable", VarType::System)
+            .unwrap();
+        assert_eq!(var.value(), Cow::Borrowed(b"edited-value"));
+
+        Ok(())
+    }
 }
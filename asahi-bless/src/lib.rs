@@ -1,13 +1,17 @@
 // SPDX-License-Identifier: MIT
 #![allow(dead_code)]
-use apple_nvram::{nvram_parse, VarType};
-use gpt::{disk::LogicalBlockSize, GptConfig};
+use apple_nvram::{nvram_parse, Nvram, Partition, VarType};
+use gpt::disk::LogicalBlockSize;
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    fs::{File, OpenOptions},
+    collections::{BTreeMap, HashMap},
+    fmt::{Display, Formatter},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
     io::{self, Read, Seek, SeekFrom},
     ops::Deref,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
 };
 use uuid::Uuid;
 
@@ -17,6 +21,9 @@ impl NxSuperblock {
     const SIZE: usize = 1408;
     const MAGIC: u32 = 1112758350; //'BSXN'
     const MAX_FILE_SYSTEMS: usize = 100;
+    // NX_MINIMUM_BLOCK_SIZE and NX_MAXIMUM_BLOCK_SIZE from the apfs reference
+    const MIN_BLOCK_SIZE: u32 = 4096;
+    const MAX_BLOCK_SIZE: u32 = 65536;
     fn get_buf(&mut self) -> &mut [u8] {
         &mut self.0
     }
@@ -77,6 +84,17 @@ impl KVOff<'_> {
     }
 }
 
+struct KVLoc<'a>(&'a [u8]);
+impl KVLoc<'_> {
+    const SIZE: usize = 8;
+    fn k(&self) -> NLoc<'_> {
+        NLoc(&self.0[0..4])
+    }
+    fn v(&self) -> NLoc<'_> {
+        NLoc(&self.0[4..8])
+    }
+}
+
 struct OmapKey<'a>(&'a [u8]);
 impl OmapKey<'_> {
     fn oid(&self) -> u64 {
@@ -140,41 +158,138 @@ impl ApfsSuperblock<'_> {
     }
 }
 
-const VOL_ROLE_SYSTEM: u16 = 1;
+// apfs_role in the volume superblock. the old roles are single bits, newer ones like data are
+// numbered from VOL_ROLE_SHIFT up, so roles are compared whole rather than tested as flags
+const VOL_ROLE_SYSTEM: u16 = 0x0001;
+const VOL_ROLE_PREBOOT: u16 = 0x0010;
+const VOL_ROLE_SHIFT: u16 = 6;
+const VOL_ROLE_DATA: u16 = 1 << VOL_ROLE_SHIFT;
+// far more than the handful of blocks apfs uses, a bigger value means a corrupt superblock
+const MAX_XP_DESC_LEN: u64 = 16 << 20;
 
 fn pread<T: Read + Seek>(file: &mut T, pos: u64, target: &mut [u8]) -> io::Result<()> {
     file.seek(SeekFrom::Start(pos))?;
     file.read_exact(target)
 }
 
+// a window into a single partition of a disk, with positions relative to the partition start
+struct PartitionReader<T> {
+    inner: T,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<T: Read + Seek> PartitionReader<T> {
+    fn new(inner: T, start: u64, len: u64) -> Self {
+        PartitionReader {
+            inner,
+            start,
+            len,
+            pos: 0,
+        }
+    }
+}
+
+impl<T: Read + Seek> Read for PartitionReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let count = buf.len().min(remaining as usize);
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.inner.read(&mut buf[..count])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Seek> Seek for PartitionReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+            SeekFrom::End(off) => self.len.checked_add_signed(off),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
 // should probably fix xids here
-fn lookup(_disk: &mut File, cur_node: &BTreeNodePhys, key: u64) -> Option<u64> {
-    if cur_node.level() != 0 {
-        unimplemented!();
-    }
-    if cur_node.flags() & BTreeNodePhys::FIXED_KV_SIZE != 0 {
-        let toc_off = cur_node.table_space().off() as usize + BTreeNodePhys::SIZE;
-        let key_start = toc_off + cur_node.table_space().len() as usize;
-        let val_end = cur_node.0.len()
-            - if cur_node.flags() & BTreeNodePhys::ROOT == 0 {
-                0
-            } else {
-                BTreeInfo::SIZE
-            };
-        for i in 0..cur_node.nkeys() as usize {
-            let entry = KVOff(&cur_node.0[(toc_off + i * KVOff::SIZE)..]);
-            let key_off = entry.k() as usize + key_start;
-            let map_key = OmapKey(&cur_node.0[key_off..]);
+fn lookup<T: Read + Seek>(
+    disk: &mut T,
+    cur_node: &BTreeNodePhys,
+    key: u64,
+) -> io::Result<Option<u64>> {
+    let fixed_kv_size = cur_node.flags() & BTreeNodePhys::FIXED_KV_SIZE != 0;
+    let toc_off = cur_node.table_space().off() as usize + BTreeNodePhys::SIZE;
+    let key_start = toc_off + cur_node.table_space().len() as usize;
+    let val_end = cur_node.0.len()
+        - if cur_node.flags() & BTreeNodePhys::ROOT == 0 {
+            0
+        } else {
+            BTreeInfo::SIZE
+        };
+    // every offset below comes from disk, one that points outside the node means corruption
+    let corrupt = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "omap b-tree entry points outside its node",
+        )
+    };
+    // in index nodes, the child to descend into is the last one with a key <= the one we look for
+    let mut child = None;
+    for i in 0..cur_node.nkeys() as usize {
+        let (k, v) = if fixed_kv_size {
+            let toc_entry = toc_off + i * KVOff::SIZE;
+            let entry = KVOff(
+                cur_node
+                    .0
+                    .get(toc_entry..toc_entry + KVOff::SIZE)
+                    .ok_or_else(corrupt)?,
+            );
+            (entry.k(), entry.v())
+        } else {
+            let toc_entry = toc_off + i * KVLoc::SIZE;
+            let entry = KVLoc(
+                cur_node
+                    .0
+                    .get(toc_entry..toc_entry + KVLoc::SIZE)
+                    .ok_or_else(corrupt)?,
+            );
+            (entry.k().off(), entry.v().off())
+        };
+        let key_off = k as usize + key_start;
+        let map_key = OmapKey(cur_node.0.get(key_off..key_off + 16).ok_or_else(corrupt)?);
+        let val_off = val_end.checked_sub(v as usize).ok_or_else(corrupt)?;
+        if cur_node.level() == 0 {
             if map_key.oid() == key {
-                let val_off = val_end - entry.v() as usize;
-                let val = OmapVal(&cur_node.0[val_off..]);
-                return Some(val.paddr());
+                let val = OmapVal(cur_node.0.get(val_off..val_off + 16).ok_or_else(corrupt)?);
+                return Ok(Some(val.paddr()));
             }
+        } else if map_key.oid() <= key {
+            let val = cur_node.0.get(val_off..val_off + 8).ok_or_else(corrupt)?;
+            child = Some(u64::from_le_bytes(val.try_into().unwrap()));
+        } else {
+            break;
         }
-        None
-    } else {
-        unimplemented!();
     }
+    let Some(child) = child else {
+        return Ok(None);
+    };
+    let block_size = cur_node.0.len();
+    let child_pos = child.checked_mul(block_size as u64).ok_or_else(corrupt)?;
+    let mut child_bytes = vec![0; block_size];
+    pread(disk, child_pos, &mut child_bytes)?;
+    let child_node = BTreeNodePhys(&child_bytes);
+    if child_node.level() >= cur_node.level() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "omap b-tree child node is not below its parent",
+        ));
+    }
+    lookup(disk, &child_node, key)
 }
 
 fn trim_zeroes(s: &[u8]) -> &[u8] {
@@ -186,24 +301,36 @@ fn trim_zeroes(s: &[u8]) -> &[u8] {
     s
 }
 
-fn scan_volume(disk: &mut File) -> io::Result<HashMap<Uuid, Vec<Volume>>> {
+fn scan_volume<T: Read + Seek>(disk: &mut T) -> io::Result<HashMap<Uuid, Vec<Volume>>> {
     let mut sb = NxSuperblock::new();
-    disk.read_exact(sb.get_buf())?;
+    pread(disk, 0, sb.get_buf())?;
     if sb.magic() != NxSuperblock::MAGIC {
         return Ok(HashMap::new());
     }
+    // every read and allocation below is sized by this, a corrupt value must not get that far
+    if !(NxSuperblock::MIN_BLOCK_SIZE..=NxSuperblock::MAX_BLOCK_SIZE).contains(&sb.block_size()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("container block size {} is out of range", sb.block_size()),
+        ));
+    }
     let block_size = sb.block_size() as u64;
-    for i in 0..sb.xp_desc_blocks() {
+    // the descriptor area is contiguous and small, one read is much cheaper than one per block
+    // on a slow disk
+    let desc_len = sb.xp_desc_blocks() as u64 * block_size;
+    if desc_len > MAX_XP_DESC_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "checkpoint descriptor area is too large",
+        ));
+    }
+    let mut desc = vec![0; desc_len as usize];
+    pread(disk, sb.xp_desc_base() * block_size, &mut desc)?;
+    for block in desc.chunks(block_size as usize) {
         let mut sbc = NxSuperblock::new();
-        pread(
-            disk,
-            (sb.xp_desc_base() + i as u64) * block_size,
-            sbc.get_buf(),
-        )?;
-        if sbc.magic() == NxSuperblock::MAGIC {
-            if sbc.xid() > sb.xid() {
-                sb = sbc;
-            }
+        sbc.get_buf().copy_from_slice(&block[..NxSuperblock::SIZE]);
+        if sbc.magic() == NxSuperblock::MAGIC && sbc.xid() > sb.xid() {
+            sb = sbc;
         }
     }
     let mut omap_bytes = vec![0; OmapPhys::SIZE];
@@ -213,13 +340,15 @@ fn scan_volume(disk: &mut File) -> io::Result<HashMap<Uuid, Vec<Volume>>> {
     pread(disk, omap.tree_oid() * block_size, &mut node_bytes)?;
     let node = BTreeNodePhys(&node_bytes);
     let mut vgs_found = HashMap::<Uuid, Vec<Volume>>::new();
+    let mut asb_bytes = vec![0; sb.block_size() as usize];
+    // empty slots cost no reads. deleting a volume can leave a hole, so they're skipped rather
+    // than ending the scan
     for i in 0..NxSuperblock::MAX_FILE_SYSTEMS {
         let fs_id = sb.fs_oid(i);
         if fs_id == 0 {
             continue;
         }
-        let vsb = lookup(disk, &node, fs_id);
-        let mut asb_bytes = vec![0; sb.block_size() as usize];
+        let vsb = lookup(disk, &node, fs_id)?;
         if vsb.is_none() {
             continue;
         }
@@ -228,15 +357,15 @@ fn scan_volume(disk: &mut File) -> io::Result<HashMap<Uuid, Vec<Volume>>> {
         if asb.volume_group_id().is_nil() {
             continue;
         }
-        if let Ok(name) = std::str::from_utf8(trim_zeroes(asb.volname())) {
-            vgs_found
-                .entry(asb.volume_group_id())
-                .or_default()
-                .push(Volume {
-                    name: name.to_owned(),
-                    is_system: asb.role() == VOL_ROLE_SYSTEM,
-                });
-        }
+        // a mangled name is still better than losing the volume, a group without volumes has
+        // nothing to show it by
+        vgs_found
+            .entry(asb.volume_group_id())
+            .or_default()
+            .push(Volume {
+                name: String::from_utf8_lossy(trim_zeroes(asb.volname())).into_owned(),
+                is_system: asb.role() == VOL_ROLE_SYSTEM,
+            });
     }
     Ok(vgs_found)
 }
@@ -252,8 +381,35 @@ pub struct BootCandidate {
     pub part_uuid: Uuid,
     pub vg_uuid: Uuid,
     pub volumes: Vec<Volume>,
+    // partition the candidate was found on, None for one read back from nvram
+    pub source: Option<PathBuf>,
+}
+
+impl BootCandidate {
+    // volumes are only used for display, a candidate is identified by its uuids
+    pub fn matches_target(&self, other: &BootCandidate) -> bool {
+        self.part_uuid == other.part_uuid && self.vg_uuid == other.vg_uuid
+    }
+}
+
+impl PartialEq for BootCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.matches_target(other)
+    }
+}
+
+impl Eq for BootCandidate {}
+
+impl Hash for BootCandidate {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.part_uuid.hash(state);
+        self.vg_uuid.hash(state);
+    }
 }
 
+// the gpt crate decodes guids from their on-disk mixed-endian layout (first three fields little
+// endian) into the usual text form, but boot-volume spells out the on-disk bytes in order. the
+// class uuid there is the apfs partition type in that byte order too.
 fn swap_uuid(u: &Uuid) -> Uuid {
     let (a, b, c, d) = u.as_fields();
     Uuid::from_fields(a.swap_bytes(), b.swap_bytes(), c.swap_bytes(), d)
@@ -268,108 +424,441 @@ pub enum Error {
     Ambiguous,
     NvramReadError(std::io::Error),
     DiskReadError(std::io::Error),
+    // the disk could be read, but it has no (valid) gpt
+    NoPartitionTable(std::io::Error),
     VolumeNotFound,
+    NoCandidates,
+    CannotToggle,
+    BackupError(std::io::Error),
+    CacheError(std::io::Error),
+    RunningOsUnknown,
+    // the bank asked for doesn't exist or doesn't hold a valid nvram
+    InvalidBank(usize),
+    // holds the writable node to use instead
+    ReadOnlyDevice(PathBuf),
 }
 
 impl From<apple_nvram::Error> for Error {
     fn from(e: apple_nvram::Error) -> Self {
         match e {
             apple_nvram::Error::ParseError => Error::Parse,
+            apple_nvram::Error::ChecksumMismatch { .. } => Error::Parse,
+            apple_nvram::Error::InvalidBank => Error::Parse,
+            apple_nvram::Error::UnknownFormat { .. } => Error::Parse,
+            apple_nvram::Error::UnsupportedGuid => Error::Parse,
+            apple_nvram::Error::Unsupported => Error::Parse,
+            apple_nvram::Error::Blank => Error::Parse,
             apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
             apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::EraseError(e) => Error::ApplyError(e),
         }
     }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+#[derive(Debug)]
+pub struct ScanError {
+    pub disk: PathBuf,
+    pub partition: u32,
+    pub error: io::Error,
+}
+
+impl Display for ScanError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "couldn't scan partition {} of {}: {}",
+            self.partition,
+            self.disk.display(),
+            self.error
+        )
+    }
+}
+
 pub fn get_boot_candidates() -> Result<Vec<BootCandidate>> {
-    let disk = GptConfig::new()
-        .writable(false)
-        .logical_block_size(LogicalBlockSize::Lb4096)
-        .open("/dev/nvme0n1")
-        .map_err(Error::DiskReadError)?;
+    get_boot_candidates_with_errors(&mut Vec::new())
+}
+
+// like get_boot_candidates, but reports partitions that failed to scan instead of silently skipping them
+pub fn get_boot_candidates_with_errors(errors: &mut Vec<ScanError>) -> Result<Vec<BootCandidate>> {
+    let disk = File::open(DISK).map_err(Error::DiskReadError)?;
+    get_boot_candidates_from_reader(disk, Path::new(DISK), errors)
+}
+
+// m1n1 sets this from the config the asahi installer wrote, it's the esp of the running install
+const ESP_PROP: &str = "/proc/device-tree/chosen/asahi,efi-system-partition";
+
+// the partition uuid, in boot-volume byte order, of the stub container the running system was
+// booted from. there's no apfs mount to ask while linux is running, but the installer always puts
+// the esp right after the stub container it belongs to.
+pub fn get_running_part_uuid() -> Result<Uuid> {
+    let prop = fs::read(ESP_PROP).map_err(|_| Error::RunningOsUnknown)?;
+    let esp = std::str::from_utf8(&prop)
+        .ok()
+        .and_then(|s| Uuid::parse_str(s.trim_end_matches('\0')).ok())
+        .ok_or(Error::RunningOsUnknown)?;
+    let disk = File::open(DISK).map_err(Error::DiskReadError)?;
+    stub_partition_for_esp(disk, &esp)?.ok_or(Error::RunningOsUnknown)
+}
+
+fn stub_partition_for_esp<T: Read + Seek>(mut disk: T, esp: &Uuid) -> Result<Option<Uuid>> {
+    let lb_size = LogicalBlockSize::Lb4096;
+    let header =
+        gpt::header::read_header_from_arbitrary_device(&mut disk, lb_size).map_err(gpt_error)?;
+    let partitions =
+        gpt::partition::file_read_partitions(&mut disk, &header, lb_size).map_err(gpt_error)?;
+    let Some((&esp_idx, _)) = partitions.iter().find(|(_, p)| p.part_guid == *esp) else {
+        return Ok(None);
+    };
+    Ok(partitions
+        .range(..esp_idx)
+        .next_back()
+        .filter(|(_, p)| is_apfs(&p.part_type_guid))
+        .map(|(_, p)| swap_uuid(&p.part_guid)))
+}
+
+// for installs on external drives, scans every nvme and sd disk instead of just the internal one
+pub fn get_boot_candidates_all_disks(errors: &mut Vec<ScanError>) -> Result<Vec<BootCandidate>> {
     let mut cands = Vec::new();
-    for (i, v) in disk.partitions() {
-        if v.part_type_guid.guid != "7C3457EF-0000-11AA-AA11-00306543ECAC" {
+    for disk in list_disks().map_err(Error::DiskReadError)? {
+        let file = match File::open(&disk) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("couldn't open {}: {}", disk.display(), e);
+                continue;
+            }
+        };
+        match get_boot_candidates_from_reader(file, &disk, errors) {
+            Ok(found) => cands.extend(found),
+            // most likely no gpt at all, like a blank usb stick, so nothing to boot from
+            Err(Error::NoPartitionTable(e)) => log::warn!("skipping {}: {}", disk.display(), e),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(cands)
+}
+
+// /sys/block only lists whole disks, not their partitions
+fn list_disks() -> io::Result<Vec<PathBuf>> {
+    let mut disks: Vec<_> = fs::read_dir("/sys/block")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("nvme") || name.starts_with("sd"))
+        .map(|name| Path::new("/dev").join(name))
+        .collect();
+    disks.sort();
+    Ok(disks)
+}
+
+const DISK: &str = "/dev/nvme0n1";
+const APFS_TYPE: Uuid = Uuid::from_u128(0x7C3457EF_0000_11AA_AA11_00306543ECAC);
+
+// compared as uuids, the gpt crate doesn't promise a particular case for the string
+fn is_apfs(typ: &gpt::partition_types::Type) -> bool {
+    Uuid::parse_str(typ.guid).is_ok_and(|guid| guid == APFS_TYPE)
+}
+
+// the kernel adds a p between the disk and partition number if the disk name ends in a digit,
+// nvme0n1p2 but sda2
+fn partition_path(disk: &Path, index: u32) -> PathBuf {
+    let mut path = disk.as_os_str().to_owned();
+    if path
+        .to_string_lossy()
+        .ends_with(|c: char| c.is_ascii_digit())
+    {
+        path.push("p");
+    }
+    path.push(index.to_string());
+    PathBuf::from(path)
+}
+
+// the internal nvme has 4k sectors, but usb sticks, sd cards and most enclosures use 512 byte
+// ones, so if there's no table where a 4k disk would have it, look where a 512 byte one would
+fn read_partitions<T: Read + Seek>(
+    disk: &mut T,
+) -> Result<(LogicalBlockSize, BTreeMap<u32, gpt::partition::Partition>)> {
+    let read = |disk: &mut T, lb_size| {
+        let header =
+            gpt::header::read_header_from_arbitrary_device(disk, lb_size).map_err(gpt_error)?;
+        gpt::partition::file_read_partitions(disk, &header, lb_size).map_err(gpt_error)
+    };
+    match read(disk, LogicalBlockSize::Lb4096) {
+        Err(Error::NoPartitionTable(_)) => Ok((
+            LogicalBlockSize::Lb512,
+            read(disk, LogicalBlockSize::Lb512)?,
+        )),
+        partitions => Ok((LogicalBlockSize::Lb4096, partitions?)),
+    }
+}
+
+// disk_path is only used to fill in BootCandidate::source
+pub fn get_boot_candidates_from_reader<T: Read + Seek>(
+    mut disk: T,
+    disk_path: &Path,
+    errors: &mut Vec<ScanError>,
+) -> Result<Vec<BootCandidate>> {
+    let (lb_size, partitions) = read_partitions(&mut disk)?;
+    let mut cands = Vec::new();
+    for (&i, v) in partitions.iter() {
+        if !is_apfs(&v.part_type_guid) {
             continue;
         }
-        let mut part = File::open(format!("/dev/nvme0n1p{i}")).map_err(Error::DiskReadError)?;
-        for (vg_uuid, volumes) in scan_volume(&mut part).unwrap_or_default() {
+        let start = v.bytes_start(lb_size).map_err(Error::DiskReadError)?;
+        let len = v.bytes_len(lb_size).map_err(Error::DiskReadError)?;
+        let mut part = PartitionReader::new(&mut disk, start, len);
+        let vgs = match scan_volume(&mut part) {
+            Ok(vgs) => vgs,
+            Err(error) => {
+                errors.push(ScanError {
+                    disk: disk_path.to_owned(),
+                    partition: i,
+                    error,
+                });
+                continue;
+            }
+        };
+        for (vg_uuid, volumes) in vgs {
             cands.push(BootCandidate {
                 vg_uuid,
                 volumes,
                 part_uuid: swap_uuid(&v.part_guid),
+                source: Some(partition_path(disk_path, i)),
             });
         }
     }
 
-    return Ok(cands);
+    Ok(cands)
 }
 
-const ALT_BOOT_VAR: &'static [u8] = b"alt-boot-volume";
+// like get_boot_candidates_with_errors, but reuses the candidates saved in the cache file if the
+// partition table hasn't changed since. refresh always rescans and rewrites the cache.
+pub fn get_boot_candidates_cached(
+    cache: &Path,
+    refresh: bool,
+    errors: &mut Vec<ScanError>,
+) -> Result<Vec<BootCandidate>> {
+    let disk = File::open(DISK).map_err(Error::DiskReadError)?;
+    get_boot_candidates_cached_from_reader(disk, Path::new(DISK), cache, refresh, errors)
+}
 
-pub fn get_boot_volume(device: &str, next: bool) -> Result<BootCandidate> {
-    let mut file = OpenOptions::new()
+pub fn get_boot_candidates_cached_from_reader<T: Read + Seek>(
+    mut disk: T,
+    disk_path: &Path,
+    cache: &Path,
+    refresh: bool,
+    errors: &mut Vec<ScanError>,
+) -> Result<Vec<BootCandidate>> {
+    let fingerprint = gpt_fingerprint(&mut disk)?;
+    if !refresh {
+        let cached = fs::read_to_string(cache)
+            .ok()
+            .and_then(|s| parse_candidates_cache(&s, &fingerprint));
+        if let Some(cands) = cached {
+            return Ok(cands);
+        }
+    }
+    let cands = get_boot_candidates_from_reader(&mut disk, disk_path, errors)?;
+    // a partition that failed to scan might work next time, don't keep incomplete results
+    if errors.is_empty() {
+        fs::write(cache, format_candidates_cache(&fingerprint, &cands))
+            .map_err(Error::CacheError)?;
+    }
+    Ok(cands)
+}
+
+// the gpt crate reports a missing or corrupt table as Other, anything else is the disk itself
+// failing
+fn gpt_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::Other | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            Error::NoPartitionTable(e)
+        }
+        _ => Error::DiskReadError(e),
+    }
+}
+
+// changes whenever partitions are added, removed or resized
+fn gpt_fingerprint<T: Read + Seek>(disk: &mut T) -> Result<String> {
+    let header = gpt::header::read_header_from_arbitrary_device(disk, LogicalBlockSize::Lb4096)
+        .map_err(gpt_error)?;
+    Ok(format!("{} {:08x}", header.disk_guid, header.crc32_parts))
+}
+
+// one line per candidate followed by its volumes, each prefixed with 1 if it's the system volume
+fn format_candidates_cache(fingerprint: &str, cands: &[BootCandidate]) -> String {
+    let mut out = format!("gpt {}\n", fingerprint);
+    for cand in cands {
+        let source = cand.source.as_deref().unwrap_or(Path::new(""));
+        out.push_str(&format!(
+            "candidate {} {} {}\n",
+            cand.part_uuid,
+            cand.vg_uuid,
+            source.display()
+        ));
+        for vol in &cand.volumes {
+            out.push_str(&format!("volume {} {}\n", vol.is_system as u8, vol.name));
+        }
+    }
+    out
+}
+
+fn parse_candidates_cache(s: &str, fingerprint: &str) -> Option<Vec<BootCandidate>> {
+    let mut lines = s.lines();
+    if lines.next()?.strip_prefix("gpt ")? != fingerprint {
+        return None;
+    }
+    let mut cands: Vec<BootCandidate> = Vec::new();
+    for line in lines {
+        let (kind, rest) = line.split_once(' ')?;
+        match kind {
+            "candidate" => {
+                // a cache written before sources were recorded doesn't parse and gets rescanned
+                let [part_uuid, vg_uuid, source]: [&str; 3] =
+                    rest.splitn(3, ' ').collect::<Vec<_>>().try_into().ok()?;
+                cands.push(BootCandidate {
+                    part_uuid: part_uuid.parse().ok()?,
+                    vg_uuid: vg_uuid.parse().ok()?,
+                    volumes: Vec::new(),
+                    source: (!source.is_empty()).then(|| PathBuf::from(source)),
+                });
+            }
+            "volume" => {
+                let (is_system, name) = rest.split_once(' ')?;
+                cands.last_mut()?.volumes.push(Volume {
+                    name: name.to_owned(),
+                    is_system: is_system == "1",
+                });
+            }
+            _ => return None,
+        }
+    }
+    Some(cands)
+}
+
+const BOOT_VAR: &[u8] = b"boot-volume";
+const ALT_BOOT_VAR: &[u8] = b"alt-boot-volume";
+// default boot target saved by set_next_boot_persistent, owned by this tool
+const PREV_BOOT_VAR: &[u8] = b"asahi-bless-prev-boot-volume";
+
+fn warn_nvram_state(file: &File, data: &[u8], nv: &dyn Nvram<'_>) {
+    for w in apple_nvram::bank_warnings(nv) {
+        log::warn!("{}", w);
+    }
+    if let Some(info) = apple_nvram::mtd::nvram_info(file.as_raw_fd()) {
+        for w in apple_nvram::mtd::check_layout(&info, data.len(), nv.bank_size()) {
+            log::warn!("{}", w);
+        }
+    }
+}
+
+// for recovering when the firmware keeps reading another bank than the one that is active here.
+// the changes are made on top of the given bank: v3 writes them into it unless it is full, v1v2
+// writes them to the other bank with a newer generation, like every v1v2 write
+fn select_bank(nv: &mut dyn Nvram<'_>, bank: Option<usize>) -> Result<()> {
+    match bank {
+        Some(bank) => nv.set_active(bank).map_err(|_| Error::InvalidBank(bank)),
+        None => Ok(()),
+    }
+}
+
+// the read-only mtd nodes can't be opened for writing, say which node to use instead of failing
+// with a permission error
+fn open_for_write(device: &str) -> Result<File> {
+    if let Some(writable) = apple_nvram::mtd::writable_node(Path::new(device)) {
+        return Err(Error::ReadOnlyDevice(writable));
+    }
+    OpenOptions::new()
         .read(true)
         .write(true)
         .open(device)
-        .map_err(Error::NvramReadError)?;
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).map_err(Error::NvramReadError)?;
+        .map_err(Error::ApplyError)
+}
+
+// only reads the active bank, the layout warnings are left to the commands that write
+pub fn get_boot_volume(device: &str, next: bool) -> Result<BootCandidate> {
+    let mut file = File::open(device).map_err(Error::NvramReadError)?;
+    let data = apple_nvram::read_active_bank(&mut file).map_err(Error::NvramReadError)?;
     let mut nv = nvram_parse(&data)?;
 
     let active = nv.active_part_mut();
-    let v;
-    if next {
-        v = active
+    let v = if next {
+        active
             .get_variable(ALT_BOOT_VAR, VarType::System)
-            .or(active.get_variable(b"boot-volume", VarType::System))
-            .ok_or(Error::Parse);
+            .or(active.get_variable(BOOT_VAR, VarType::System))
+            .ok_or(Error::Parse)
     } else {
-        v = active
-            .get_variable(b"boot-volume", VarType::System)
-            .ok_or(Error::Parse);
-    }
-    let data = String::from_utf8(v?.value().deref().to_vec()).unwrap();
-    let [_, part_uuid, part_vg_uuid]: [&str; 3] =
-        data.split(":").collect::<Vec<&str>>().try_into().unwrap();
+        active
+            .get_variable(BOOT_VAR, VarType::System)
+            .ok_or(Error::Parse)
+    };
+    parse_boot_volume(v?.value().deref())
+}
+
+// inverse of boot_volume_value
+fn parse_boot_volume(value: &[u8]) -> Result<BootCandidate> {
+    let data = std::str::from_utf8(value).map_err(|_| Error::Parse)?;
+    let [_, part_uuid, part_vg_uuid]: [&str; 3] = data
+        .split(":")
+        .collect::<Vec<&str>>()
+        .try_into()
+        .map_err(|_| Error::Parse)?;
 
     Ok(BootCandidate {
         volumes: Vec::new(),
-        part_uuid: Uuid::parse_str(part_uuid).unwrap(),
-        vg_uuid: Uuid::parse_str(part_vg_uuid).unwrap(),
+        part_uuid: Uuid::parse_str(part_uuid).map_err(|_| Error::Parse)?,
+        vg_uuid: Uuid::parse_str(part_vg_uuid).map_err(|_| Error::Parse)?,
+        source: None,
     })
 }
 
-pub fn clear_next_boot(device: &str) -> Result<bool> {
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(device)
-        .map_err(Error::ApplyError)?;
+// clears the next boot target. alt-boot-volume is a one-shot target that the firmware consumes on
+// the next boot, so it is simply removed. a persistent next boot target overwrites boot-volume, so
+// the default it replaced is restored from PREV_BOOT_VAR.
+pub fn clear_next_boot(device: &str, bank: Option<usize>) -> Result<bool> {
+    let mut file = open_for_write(device)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data).map_err(Error::ApplyError)?;
     let mut nv = nvram_parse(&data)?;
+    warn_nvram_state(&file, &data, &*nv);
+    select_bank(&mut *nv, bank)?;
     nv.prepare_for_write();
-    if nv.active_part_mut().get_variable(ALT_BOOT_VAR, VarType::System).is_none() {
+    if !clear_next_boot_part(nv.active_part_mut()) {
         return Ok(false);
     }
-    nv.active_part_mut().remove_variable(
-        ALT_BOOT_VAR,
-        VarType::System,
-    );
     nv.apply(&mut file)?;
     Ok(true)
 }
 
-pub fn set_boot_volume(device: &str, cand: &BootCandidate, next: bool) -> Result<()> {
-    let mut nvram_key: &[u8] = b"boot-volume".as_ref();
+fn clear_next_boot_part(part: &mut dyn Partition) -> bool {
+    let had_alt = part.get_variable(ALT_BOOT_VAR, VarType::System).is_some();
+    part.remove_variable(ALT_BOOT_VAR, VarType::System);
+    let prev = part
+        .get_variable(PREV_BOOT_VAR, VarType::System)
+        .map(|v| v.value().into_owned());
+    if let Some(prev) = &prev {
+        part.insert_variable(BOOT_VAR, Cow::Owned(prev.clone()), VarType::System);
+        part.remove_variable(PREV_BOOT_VAR, VarType::System);
+    }
+    had_alt || prev.is_some()
+}
+
+fn insert_boot_volume(part: &mut dyn Partition, cand: &BootCandidate, next: bool) {
+    let mut nvram_key: &[u8] = BOOT_VAR;
     if next {
-        nvram_key = ALT_BOOT_VAR.as_ref();
+        nvram_key = ALT_BOOT_VAR;
     }
 
-    let boot_str = format!(
+    part.insert_variable(
+        nvram_key,
+        Cow::Owned(boot_volume_value(cand).into_bytes()),
+        VarType::System,
+    );
+}
+
+// the exact string stored in boot-volume or alt-boot-volume for this candidate
+pub fn boot_volume_value(cand: &BootCandidate) -> String {
+    format!(
         "EF57347C-0000-AA11-AA11-00306543ECAC:{}:{}",
         cand.part_uuid
             .hyphenated()
@@ -377,21 +866,818 @@ pub fn set_boot_volume(device: &str, cand: &BootCandidate, next: bool) -> Result
         cand.vg_uuid
             .hyphenated()
             .encode_upper(&mut Uuid::encode_buffer())
-    );
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(device)
-        .map_err(Error::ApplyError)?;
+    )
+}
+
+// for the usual setup of one macOS and one Linux install, switches to the candidate that isn't
+// the default boot target. with next, only the next boot goes there. returns the selected one.
+pub fn toggle_boot(device: &str, next: bool, bank: Option<usize>) -> Result<BootCandidate> {
+    let current = get_boot_volume(device, false)?;
+    let cand = other_candidate(get_boot_candidates()?, &current)?;
+    set_boot_volume(device, &cand, next, bank)?;
+    Ok(cand)
+}
+
+// only well defined with exactly two candidates, one of which is the current target
+fn other_candidate(cands: Vec<BootCandidate>, current: &BootCandidate) -> Result<BootCandidate> {
+    if cands.is_empty() {
+        return Err(Error::NoCandidates);
+    }
+    let [a, b]: [BootCandidate; 2] = cands.try_into().map_err(|_| Error::CannotToggle)?;
+    match (a.matches_target(current), b.matches_target(current)) {
+        (true, false) => Ok(b),
+        (false, true) => Ok(a),
+        _ => Err(Error::CannotToggle),
+    }
+}
+
+pub fn set_boot_volume(
+    device: &str,
+    cand: &BootCandidate,
+    next: bool,
+    bank: Option<usize>,
+) -> Result<()> {
+    if next {
+        set_boot_volumes(device, None, Some(cand), bank)
+    } else {
+        set_boot_volumes(device, Some(cand), None, bank)
+    }
+}
+
+// sets the default and/or next boot target with a single nvram write
+pub fn set_boot_volumes(
+    device: &str,
+    default: Option<&BootCandidate>,
+    next: Option<&BootCandidate>,
+    bank: Option<usize>,
+) -> Result<()> {
+    let mut file = open_for_write(device)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data).map_err(Error::ApplyError)?;
     let mut nv = nvram_parse(&data)?;
+    warn_nvram_state(&file, &data, &*nv);
+    select_bank(&mut *nv, bank)?;
     nv.prepare_for_write();
-    nv.active_part_mut().insert_variable(
-        nvram_key,
-        Cow::Owned(boot_str.into_bytes()),
-        VarType::System,
-    );
+    if let Some(cand) = default {
+        // an explicitly chosen default replaces whatever a persistent next boot would restore
+        nv.active_part_mut()
+            .remove_variable(PREV_BOOT_VAR, VarType::System);
+        insert_boot_volume(nv.active_part_mut(), cand, false);
+    }
+    if let Some(cand) = next {
+        insert_boot_volume(nv.active_part_mut(), cand, true);
+    }
+    // appended like any other change. apply_in_place would save the space, but erases the only
+    // current copy of the bank before writing it back
     nv.apply(&mut file)?;
     Ok(())
 }
+
+// sets a next boot target that, unlike alt-boot-volume, survives reboots until clear_next_boot is
+// called. it replaces boot-volume and keeps the previous default in PREV_BOOT_VAR. if a persistent
+// target is already set, the originally saved default is kept.
+pub fn set_next_boot_persistent(
+    device: &str,
+    cand: &BootCandidate,
+    bank: Option<usize>,
+) -> Result<()> {
+    let mut file = open_for_write(device)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).map_err(Error::ApplyError)?;
+    let mut nv = nvram_parse(&data)?;
+    warn_nvram_state(&file, &data, &*nv);
+    select_bank(&mut *nv, bank)?;
+    nv.prepare_for_write();
+    set_next_boot_persistent_part(nv.active_part_mut(), cand);
+    nv.apply(&mut file)?;
+    Ok(())
+}
+
+fn set_next_boot_persistent_part(part: &mut dyn Partition, cand: &BootCandidate) {
+    if part.get_variable(PREV_BOOT_VAR, VarType::System).is_none() {
+        if let Some(prev) = part
+            .get_variable(BOOT_VAR, VarType::System)
+            .map(|v| v.value().into_owned())
+        {
+            part.insert_variable(PREV_BOOT_VAR, Cow::Owned(prev), VarType::System);
+        }
+    }
+    part.remove_variable(ALT_BOOT_VAR, VarType::System);
+    insert_boot_volume(part, cand, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpt::{partition_types, GptConfig};
+    use std::io::{Cursor, Write};
+
+    const BLOCK_SIZE: usize = 4096;
+    const OMAP_BLOCK: u64 = 1;
+    const TREE_BLOCK: u64 = 2;
+    const VOLUME_BLOCK: u64 = 3;
+    const FIRST_FS_OID: u64 = 1026;
+
+    struct TestVolume {
+        name: &'static str,
+        vg_uuid: Uuid,
+        role: u16,
+    }
+
+    fn put(block: &mut [u8], at: usize, bytes: &[u8]) {
+        block[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // builds an omap b-tree node, values are physical addresses on leaves and child oids otherwise
+    fn tree_node(level: u16, entries: &[(u64, u64)], root: bool, fixed_kv_size: bool) -> Vec<u8> {
+        let mut node = vec![0; BLOCK_SIZE];
+        let mut flags = 0;
+        if fixed_kv_size {
+            flags |= BTreeNodePhys::FIXED_KV_SIZE;
+        }
+        if root {
+            flags |= BTreeNodePhys::ROOT;
+        }
+        let val_size = if level == 0 { 16 } else { 8 };
+        let toc_entry_size = if fixed_kv_size {
+            KVOff::SIZE
+        } else {
+            KVLoc::SIZE
+        };
+        let toc_len = entries.len() * toc_entry_size;
+        put(&mut node, 32, &flags.to_le_bytes());
+        put(&mut node, 34, &level.to_le_bytes());
+        put(&mut node, 36, &(entries.len() as u32).to_le_bytes());
+        put(&mut node, 40, &0u16.to_le_bytes());
+        put(&mut node, 42, &(toc_len as u16).to_le_bytes());
+        let toc_off = BTreeNodePhys::SIZE;
+        let key_start = toc_off + toc_len;
+        let val_end = BLOCK_SIZE - if root { BTreeInfo::SIZE } else { 0 };
+        for (i, &(oid, val)) in entries.iter().enumerate() {
+            let k = i * 16;
+            let v = (i + 1) * val_size;
+            let toc_entry = toc_off + i * toc_entry_size;
+            if fixed_kv_size {
+                put(&mut node, toc_entry, &(k as u16).to_le_bytes());
+                put(&mut node, toc_entry + 2, &(v as u16).to_le_bytes());
+            } else {
+                put(&mut node, toc_entry, &(k as u16).to_le_bytes());
+                put(&mut node, toc_entry + 2, &16u16.to_le_bytes());
+                put(&mut node, toc_entry + 4, &(v as u16).to_le_bytes());
+                put(&mut node, toc_entry + 6, &(val_size as u16).to_le_bytes());
+            }
+            put(&mut node, key_start + k, &oid.to_le_bytes());
+            put(&mut node, key_start + k + 8, &1u64.to_le_bytes());
+            put(&mut node, val_end - v + val_size - 8, &val.to_le_bytes());
+        }
+        node
+    }
+
+    fn container(volumes: &[TestVolume]) -> Vec<u8> {
+        container_with_tree(volumes, None, true)
+    }
+
+    // with leaf_len set, the omap tree gets an index root with leaves of up to leaf_len entries
+    fn container_with_tree(
+        volumes: &[TestVolume],
+        leaf_len: Option<usize>,
+        fixed_kv_size: bool,
+    ) -> Vec<u8> {
+        let mut sb = vec![0; BLOCK_SIZE];
+        put(&mut sb, 16, &1u64.to_le_bytes());
+        put(&mut sb, 32, &NxSuperblock::MAGIC.to_le_bytes());
+        put(&mut sb, 36, &(BLOCK_SIZE as u32).to_le_bytes());
+        put(&mut sb, 160, &OMAP_BLOCK.to_le_bytes());
+        for i in 0..volumes.len() {
+            put(
+                &mut sb,
+                184 + 8 * i,
+                &(FIRST_FS_OID + i as u64).to_le_bytes(),
+            );
+        }
+
+        let mut omap = vec![0; BLOCK_SIZE];
+        put(&mut omap, 48, &TREE_BLOCK.to_le_bytes());
+
+        let leaves = match leaf_len {
+            Some(len) => volumes.len().div_ceil(len),
+            None => 0,
+        };
+        let volume_block = TREE_BLOCK + 1 + leaves as u64;
+        let entries: Vec<_> = (0..volumes.len() as u64)
+            .map(|i| (FIRST_FS_OID + i, volume_block + i))
+            .collect();
+
+        let mut data = [sb, omap].concat();
+        match leaf_len {
+            Some(len) => {
+                let children: Vec<_> = entries
+                    .chunks(len)
+                    .enumerate()
+                    .map(|(i, chunk)| (chunk[0].0, TREE_BLOCK + 1 + i as u64))
+                    .collect();
+                data.extend_from_slice(&tree_node(1, &children, true, fixed_kv_size));
+                for chunk in entries.chunks(len) {
+                    data.extend_from_slice(&tree_node(0, chunk, false, fixed_kv_size));
+                }
+            }
+            None => data.extend_from_slice(&tree_node(0, &entries, true, fixed_kv_size)),
+        }
+        for vol in volumes {
+            let mut asb = vec![0; BLOCK_SIZE];
+            put(&mut asb, 240, Uuid::new_v4().as_bytes());
+            put(&mut asb, 704, vol.name.as_bytes());
+            put(&mut asb, 964, &vol.role.to_le_bytes());
+            put(&mut asb, 1008, vol.vg_uuid.as_bytes());
+            data.extend_from_slice(&asb);
+        }
+        data
+    }
+
+    // returns a disk image with one partition per container and the partition guids
+    fn disk_image(containers: &[Vec<u8>]) -> (Box<dyn gpt::DiskDevice>, Vec<Uuid>) {
+        disk_image_with_sectors(containers, LogicalBlockSize::Lb4096)
+    }
+
+    fn disk_image_with_sectors(
+        containers: &[Vec<u8>],
+        lb_size: LogicalBlockSize,
+    ) -> (Box<dyn gpt::DiskDevice>, Vec<Uuid>) {
+        let size = BLOCK_SIZE * 16 + containers.iter().map(|c| c.len()).sum::<usize>();
+        let dev = Box::new(Cursor::new(vec![0u8; size]));
+        let mut disk = GptConfig::new()
+            .writable(true)
+            .initialized(false)
+            .logical_block_size(lb_size)
+            .create_from_device(dev, None)
+            .unwrap();
+        disk.update_partitions(Default::default()).unwrap();
+        let mut ids = Vec::new();
+        for c in containers {
+            let id = disk
+                .add_partition("test", c.len() as u64, partition_types::MACOS_APFS, 0, None)
+                .unwrap();
+            ids.push(id);
+        }
+        let partitions = disk.partitions().clone();
+        let mut dev = disk.write().unwrap();
+        let mut guids = Vec::new();
+        for (id, c) in ids.iter().zip(containers) {
+            let part = &partitions[id];
+            dev.seek(SeekFrom::Start(part.bytes_start(lb_size).unwrap()))
+                .unwrap();
+            dev.write_all(c).unwrap();
+            guids.push(part.part_guid);
+        }
+        (dev, guids)
+    }
+
+    #[test]
+    fn test_candidates_from_reader() -> Result<()> {
+        let vg = Uuid::new_v4();
+        let (disk, guids) = disk_image(&[container(&[
+            TestVolume {
+                name: "Macintosh HD - Data",
+                vg_uuid: vg,
+                role: VOL_ROLE_DATA,
+            },
+            TestVolume {
+                name: "Macintosh HD",
+                vg_uuid: vg,
+                role: VOL_ROLE_SYSTEM,
+            },
+            TestVolume {
+                name: "Preboot",
+                vg_uuid: Uuid::nil(),
+                role: VOL_ROLE_PREBOOT,
+            },
+        ])]);
+
+        let mut errors = Vec::new();
+        let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+        assert!(errors.is_empty());
+        assert_eq!(cands.len(), 1);
+        assert_eq!(cands[0].vg_uuid, vg);
+        assert_eq!(cands[0].part_uuid, swap_uuid(&guids[0]));
+        let names: Vec<_> = cands[0].volumes.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["Macintosh HD - Data", "Macintosh HD"]);
+        assert!(!cands[0].volumes[0].is_system);
+        assert!(cands[0].volumes[1].is_system);
+
+        Ok(())
+    }
+
+    // external disks usually have 512 byte sectors, which moves the gpt header to byte 512
+    #[test]
+    fn test_candidates_from_512_byte_sector_disk() -> Result<()> {
+        let vg = Uuid::new_v4();
+        let (disk, guids) = disk_image_with_sectors(
+            &[container(&[TestVolume {
+                name: "Asahi Linux",
+                vg_uuid: vg,
+                role: VOL_ROLE_SYSTEM,
+            }])],
+            LogicalBlockSize::Lb512,
+        );
+
+        let mut errors = Vec::new();
+        let cands = get_boot_candidates_from_reader(disk, Path::new("/dev/sda"), &mut errors)?;
+        assert!(errors.is_empty());
+        assert_eq!(cands.len(), 1);
+        assert_eq!(cands[0].vg_uuid, vg);
+        assert_eq!(cands[0].part_uuid, swap_uuid(&guids[0]));
+        assert_eq!(cands[0].volumes[0].name, "Asahi Linux");
+        assert_eq!(cands[0].source.as_deref(), Some(Path::new("/dev/sda1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_role() -> Result<()> {
+        let vg = Uuid::new_v4();
+        // the data role shares no bits with system, a volume with any other role is never the
+        // system one even if it's the only volume in the group
+        for (role, is_system) in [
+            (VOL_ROLE_SYSTEM, true),
+            (VOL_ROLE_DATA, false),
+            (VOL_ROLE_DATA | VOL_ROLE_SYSTEM, false),
+            (0, false),
+        ] {
+            let (disk, _) = disk_image(&[container(&[TestVolume {
+                name: "Volume",
+                vg_uuid: vg,
+                role,
+            }])]);
+            let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut Vec::new())?;
+            assert_eq!(cands[0].volumes[0].is_system, is_system, "role {:#x}", role);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_candidates_from_multiple_partitions() -> Result<()> {
+        let vg1 = Uuid::new_v4();
+        let vg2 = Uuid::new_v4();
+        let (disk, guids) = disk_image(&[
+            container(&[TestVolume {
+                name: "Macintosh HD",
+                vg_uuid: vg1,
+                role: VOL_ROLE_SYSTEM,
+            }]),
+            container(&[TestVolume {
+                name: "Asahi Linux",
+                vg_uuid: vg2,
+                role: VOL_ROLE_SYSTEM,
+            }]),
+        ]);
+
+        let mut errors = Vec::new();
+        let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+        assert!(errors.is_empty());
+        assert_eq!(cands.len(), 2);
+        assert_eq!(cands[0].vg_uuid, vg1);
+        assert_eq!(cands[0].part_uuid, swap_uuid(&guids[0]));
+        assert_eq!(cands[1].vg_uuid, vg2);
+        assert_eq!(cands[1].part_uuid, swap_uuid(&guids[1]));
+        assert_eq!(cands[0].source, Some(PathBuf::from("/dev/nvme0n1p1")));
+        assert_eq!(cands[1].source, Some(PathBuf::from("/dev/nvme0n1p2")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_apfs() {
+        let typ = |guid| partition_types::Type {
+            guid,
+            os: partition_types::OperatingSystem::MacOs,
+        };
+        assert!(is_apfs(&partition_types::MACOS_APFS));
+        assert!(is_apfs(&typ("7C3457EF-0000-11AA-AA11-00306543ECAC")));
+        assert!(is_apfs(&typ("7c3457ef-0000-11aa-aa11-00306543ecac")));
+        assert!(!is_apfs(&typ("48465300-0000-11AA-AA11-00306543ECAC")));
+        assert!(!is_apfs(&typ("not a guid")));
+    }
+
+    #[test]
+    fn test_partition_path() {
+        assert_eq!(
+            partition_path(Path::new("/dev/nvme0n1"), 2),
+            PathBuf::from("/dev/nvme0n1p2")
+        );
+        assert_eq!(
+            partition_path(Path::new("/dev/sda"), 2),
+            PathBuf::from("/dev/sda2")
+        );
+    }
+
+    #[test]
+    fn test_candidates_cache() -> Result<()> {
+        let vg = Uuid::new_v4();
+        let (mut disk, _) = disk_image(&[container(&[TestVolume {
+            name: "Macintosh HD",
+            vg_uuid: vg,
+            role: VOL_ROLE_SYSTEM,
+        }])]);
+        let cache = std::env::temp_dir().join(format!("asahi-bless-test-{}", std::process::id()));
+        let _ = fs::remove_file(&cache);
+        let names = |cands: &[BootCandidate]| {
+            cands
+                .iter()
+                .flat_map(|c| c.volumes.iter().map(|v| v.name.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let cands = get_boot_candidates_cached_from_reader(
+            &mut disk,
+            Path::new(DISK),
+            &cache,
+            false,
+            &mut Vec::new(),
+        )?;
+        assert_eq!(names(&cands), ["Macintosh HD"]);
+        // edit the cache to tell apart cached and scanned results
+        let edited = fs::read_to_string(&cache)
+            .unwrap()
+            .replace("Macintosh HD", "Cached");
+        fs::write(&cache, edited).unwrap();
+        let cached = get_boot_candidates_cached_from_reader(
+            &mut disk,
+            Path::new(DISK),
+            &cache,
+            false,
+            &mut Vec::new(),
+        )?;
+        assert_eq!(cached, cands);
+        assert_eq!(cached[0].source, cands[0].source);
+        assert!(cached[0].volumes[0].is_system);
+        assert_eq!(names(&cached), ["Cached"]);
+
+        let refreshed = get_boot_candidates_cached_from_reader(
+            &mut disk,
+            Path::new(DISK),
+            &cache,
+            true,
+            &mut Vec::new(),
+        )?;
+        assert_eq!(names(&refreshed), ["Macintosh HD"]);
+
+        // a different partition table invalidates the cache
+        fs::write(
+            &cache,
+            fs::read_to_string(&cache)
+                .unwrap()
+                .replace("Macintosh HD", "Cached"),
+        )
+        .unwrap();
+        let (other, _) = disk_image(&[container(&[TestVolume {
+            name: "Asahi Linux",
+            vg_uuid: vg,
+            role: VOL_ROLE_SYSTEM,
+        }])]);
+        let rescanned = get_boot_candidates_cached_from_reader(
+            other,
+            Path::new(DISK),
+            &cache,
+            false,
+            &mut Vec::new(),
+        )?;
+        assert_eq!(names(&rescanned), ["Asahi Linux"]);
+
+        fs::remove_file(&cache).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_without_gpt() {
+        let blank = Cursor::new(vec![0u8; BLOCK_SIZE * 16]);
+        assert!(matches!(
+            get_boot_candidates_from_reader(blank, Path::new(DISK), &mut Vec::new()),
+            Err(Error::NoPartitionTable(_))
+        ));
+        // a gpt without apfs partitions is fine, there's just nothing to boot
+        let (disk, _) = disk_image(&[]);
+        assert!(
+            get_boot_candidates_from_reader(disk, Path::new(DISK), &mut Vec::new())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_stub_partition_for_esp() -> Result<()> {
+        let (mut disk, guids) = disk_image(&[container(&[]), container(&[]), container(&[])]);
+        assert_eq!(
+            stub_partition_for_esp(&mut disk, &guids[2])?,
+            Some(swap_uuid(&guids[1]))
+        );
+        // nothing before the first partition
+        assert_eq!(stub_partition_for_esp(&mut disk, &guids[0])?, None);
+        assert_eq!(stub_partition_for_esp(&mut disk, &Uuid::new_v4())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_newest_checkpoint_is_used() -> Result<()> {
+        let vg = Uuid::new_v4();
+        let mut c = container(&[TestVolume {
+            name: "Macintosh HD",
+            vg_uuid: vg,
+            role: VOL_ROLE_SYSTEM,
+        }]);
+        // the checkpoint area holds a garbage block and a newer copy of the superblock, while
+        // the one in block 0 is stale and lists no volumes
+        let mut newer = c[..BLOCK_SIZE].to_vec();
+        put(&mut newer, 16, &2u64.to_le_bytes());
+        let desc_base = (c.len() / BLOCK_SIZE) as u64;
+        put(&mut c, 104, &2u32.to_le_bytes());
+        put(&mut c, 112, &desc_base.to_le_bytes());
+        put(&mut c, 184, &0u64.to_le_bytes());
+        c.extend_from_slice(&[0xff; BLOCK_SIZE]);
+        c.extend_from_slice(&newer);
+        let (disk, _) = disk_image(&[c]);
+
+        let mut errors = Vec::new();
+        let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+        assert!(errors.is_empty());
+        assert_eq!(cands.len(), 1);
+        assert_eq!(cands[0].volumes[0].name, "Macintosh HD");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_block_size_is_reported() -> Result<()> {
+        for block_size in [0u32, 1024, 1 << 30] {
+            let mut c = container(&[TestVolume {
+                name: "Macintosh HD",
+                vg_uuid: Uuid::new_v4(),
+                role: VOL_ROLE_SYSTEM,
+            }]);
+            put(&mut c, 36, &block_size.to_le_bytes());
+            let (disk, _) = disk_image(&[c]);
+
+            let mut errors = Vec::new();
+            let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+            assert!(cands.is_empty());
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].error.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_container_is_reported() -> Result<()> {
+        let vg = Uuid::new_v4();
+        let mut broken = container(&[TestVolume {
+            name: "Macintosh HD",
+            vg_uuid: vg,
+            role: VOL_ROLE_SYSTEM,
+        }]);
+        // point the omap past the end of the partition
+        put(&mut broken, 160, &1000u64.to_le_bytes());
+        let (disk, _) = disk_image(&[
+            broken,
+            container(&[TestVolume {
+                name: "Asahi Linux",
+                vg_uuid: vg,
+                role: VOL_ROLE_SYSTEM,
+            }]),
+        ]);
+
+        let mut errors = Vec::new();
+        let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+        assert_eq!(cands.len(), 1);
+        assert_eq!(cands[0].volumes[0].name, "Asahi Linux");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].partition, 1);
+        assert_eq!(errors[0].error.kind(), io::ErrorKind::UnexpectedEof);
+
+        Ok(())
+    }
+
+    fn check_omap_tree(leaf_len: Option<usize>, fixed_kv_size: bool) -> Result<()> {
+        let volumes: Vec<_> = ["Macintosh HD", "Asahi Linux", "Other", "More", "Last"]
+            .into_iter()
+            .map(|name| TestVolume {
+                name,
+                vg_uuid: Uuid::new_v4(),
+                role: VOL_ROLE_SYSTEM,
+            })
+            .collect();
+        let (disk, _) = disk_image(&[container_with_tree(&volumes, leaf_len, fixed_kv_size)]);
+
+        let mut errors = Vec::new();
+        let mut cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+        assert!(errors.is_empty());
+        assert_eq!(cands.len(), volumes.len());
+        cands.sort_by_key(|c| c.volumes[0].name.clone());
+        let mut names: Vec<_> = volumes.iter().map(|v| v.name).collect();
+        names.sort();
+        for (cand, name) in cands.iter().zip(names) {
+            assert_eq!(cand.volumes[0].name, name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_level_omap() -> Result<()> {
+        check_omap_tree(Some(2), true)
+    }
+
+    #[test]
+    fn test_variable_kv_size_omap() -> Result<()> {
+        check_omap_tree(None, false)?;
+        check_omap_tree(Some(2), false)
+    }
+
+    #[test]
+    fn test_corrupt_omap_offsets_are_reported() -> Result<()> {
+        let volumes = [TestVolume {
+            name: "Macintosh HD",
+            vg_uuid: Uuid::new_v4(),
+            role: VOL_ROLE_SYSTEM,
+        }];
+        let tree = TREE_BLOCK as usize * BLOCK_SIZE;
+        let toc = tree + BTreeNodePhys::SIZE;
+        let mut key_past_end = container(&volumes);
+        put(&mut key_past_end, toc, &0xFFFFu16.to_le_bytes());
+        let mut val_before_start = container(&volumes);
+        put(&mut val_before_start, toc + 2, &0xFFFFu16.to_le_bytes());
+        let mut toc_past_end = container(&volumes);
+        put(&mut toc_past_end, tree + 40, &0xFFFFu16.to_le_bytes());
+        // the index root's only child, placed so that its position overflows
+        let mut child_overflow = container_with_tree(&volumes, Some(1), true);
+        put(
+            &mut child_overflow,
+            tree + BLOCK_SIZE - BTreeInfo::SIZE - 8,
+            &(u64::MAX / 2).to_le_bytes(),
+        );
+
+        for c in [key_past_end, val_before_start, toc_past_end, child_overflow] {
+            let (disk, _) = disk_image(&[c]);
+            let mut errors = Vec::new();
+            let cands = get_boot_candidates_from_reader(disk, Path::new(DISK), &mut errors)?;
+            assert!(cands.is_empty());
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].error.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    fn empty_partition() -> apple_nvram::v1v2::Partition<'static> {
+        let section = |name| apple_nvram::v1v2::Section {
+            header: apple_nvram::v1v2::CHRPHeader {
+                name,
+                size: 0x100,
+                signature: 0x70,
+            },
+            values: HashMap::new(),
+        };
+        apple_nvram::v1v2::Partition {
+            header: apple_nvram::v1v2::CHRPHeader {
+                name: b"nvram",
+                size: 0x200,
+                signature: 0x5A,
+            },
+            generation: 1,
+            common: section(b"common"),
+            system: section(b"system"),
+        }
+    }
+
+    fn boot_value(part: &dyn Partition, key: &[u8]) -> Option<Vec<u8>> {
+        part.get_variable(key, VarType::System)
+            .map(|v| v.value().into_owned())
+    }
+
+    #[test]
+    fn test_boot_volume_round_trip() -> Result<()> {
+        let part_guid = Uuid::parse_str("0F3A2D6E-54B1-4C8E-9A77-123456789ABC").unwrap();
+        let vg_uuid = Uuid::from_u128(0x1234);
+        let cand = BootCandidate {
+            part_uuid: swap_uuid(&part_guid),
+            vg_uuid,
+            volumes: Vec::new(),
+            source: None,
+        };
+        // nvram has the bytes as they are stored in the gpt entry
+        assert_eq!(cand.part_uuid.as_bytes(), &part_guid.to_bytes_le());
+
+        let value = boot_volume_value(&cand);
+        assert_eq!(
+            value,
+            "EF57347C-0000-AA11-AA11-00306543ECAC:6E2D3A0F-B154-8E4C-9A77-123456789ABC:\
+             00000000-0000-0000-0000-000000001234"
+        );
+        let class = Uuid::parse_str(value.split(':').next().unwrap()).unwrap();
+        assert_eq!(class, swap_uuid(&APFS_TYPE));
+
+        let parsed = parse_boot_volume(value.as_bytes())?;
+        assert!(parsed.matches_target(&cand));
+        assert_eq!(swap_uuid(&parsed.part_uuid), part_guid);
+        assert_eq!(parsed.vg_uuid, vg_uuid);
+
+        assert!(matches!(parse_boot_volume(b"garbage"), Err(Error::Parse)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_candidate() {
+        let cand = |n| BootCandidate {
+            part_uuid: Uuid::from_u128(n),
+            vg_uuid: Uuid::from_u128(n + 1),
+            volumes: Vec::new(),
+            source: None,
+        };
+        let other = other_candidate(vec![cand(1), cand(3)], &cand(1)).unwrap();
+        assert!(other.matches_target(&cand(3)));
+        let other = other_candidate(vec![cand(1), cand(3)], &cand(3)).unwrap();
+        assert!(other.matches_target(&cand(1)));
+
+        assert!(matches!(
+            other_candidate(vec![], &cand(1)),
+            Err(Error::NoCandidates)
+        ));
+        assert!(matches!(
+            other_candidate(vec![cand(1), cand(3), cand(5)], &cand(1)),
+            Err(Error::CannotToggle)
+        ));
+        // the current target has to be one of the two
+        assert!(matches!(
+            other_candidate(vec![cand(1), cand(3)], &cand(5)),
+            Err(Error::CannotToggle)
+        ));
+    }
+
+    #[test]
+    fn test_boot_volume_on_device() -> Result<()> {
+        let cand = |n| BootCandidate {
+            part_uuid: Uuid::from_u128(n),
+            vg_uuid: Uuid::from_u128(n + 1),
+            volumes: Vec::new(),
+            source: None,
+        };
+        let path = std::env::temp_dir().join(format!("asahi-bless-nvram-{}", std::process::id()));
+        fs::write(&path, apple_nvram::v3::Nvram::empty(2, 0x4000, 0xC000)).unwrap();
+        let device = path.to_str().unwrap();
+
+        set_boot_volume(device, &cand(1), false, None)?;
+        assert!(get_boot_volume(device, false)?.matches_target(&cand(1)));
+        // without a next boot target, the default is used
+        assert!(get_boot_volume(device, true)?.matches_target(&cand(1)));
+
+        set_boot_volume(device, &cand(3), true, None)?;
+        assert!(get_boot_volume(device, true)?.matches_target(&cand(3)));
+        assert!(get_boot_volume(device, false)?.matches_target(&cand(1)));
+
+        assert!(clear_next_boot(device, None)?);
+        assert!(get_boot_volume(device, true)?.matches_target(&cand(1)));
+
+        // the second bank of a fresh image is still empty, there is nothing to write on top of
+        assert!(matches!(
+            set_boot_volume(device, &cand(5), false, Some(1)),
+            Err(Error::InvalidBank(1))
+        ));
+        assert!(matches!(
+            clear_next_boot(device, Some(2)),
+            Err(Error::InvalidBank(2))
+        ));
+        set_boot_volume(device, &cand(5), false, Some(0))?;
+        let data = fs::read(device).unwrap();
+        let nv = apple_nvram::nvram_parse(&data)?;
+        assert_eq!(nv.active_bank(), 0);
+        assert!(get_boot_volume(device, false)?.matches_target(&cand(5)));
+
+        fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_persistent_next_boot_restores_default() {
+        let cand = |n| BootCandidate {
+            part_uuid: Uuid::from_u128(n),
+            vg_uuid: Uuid::from_u128(n + 1),
+            volumes: Vec::new(),
+            source: None,
+        };
+        let mut part = empty_partition();
+        insert_boot_volume(&mut part, &cand(1), false);
+        let default = boot_value(&part, BOOT_VAR);
+
+        set_next_boot_persistent_part(&mut part, &cand(3));
+        set_next_boot_persistent_part(&mut part, &cand(5));
+        assert_eq!(boot_value(&part, PREV_BOOT_VAR), default);
+        assert_ne!(boot_value(&part, BOOT_VAR), default);
+
+        assert!(clear_next_boot_part(&mut part));
+        assert_eq!(boot_value(&part, BOOT_VAR), default);
+        assert_eq!(boot_value(&part, PREV_BOOT_VAR), None);
+        assert!(!clear_next_boot_part(&mut part));
+
+        insert_boot_volume(&mut part, &cand(3), true);
+        assert!(clear_next_boot_part(&mut part));
+        assert_eq!(boot_value(&part, ALT_BOOT_VAR), None);
+        assert_eq!(boot_value(&part, BOOT_VAR), default);
+    }
+}
@@ -1,10 +1,13 @@
 // SPDX-License-Identifier: MIT
 #![allow(dead_code)]
-use asahi_bless::{get_boot_candidates, get_boot_volume, set_boot_volume, clear_next_boot,  BootCandidate, Error, Volume};
+use asahi_bless::{boot_volume_value, get_boot_candidates_all_disks, get_boot_candidates_cached, get_boot_candidates_with_errors, get_boot_volume, get_running_part_uuid, set_boot_volumes, set_next_boot_persistent, clear_next_boot, toggle_boot, BootCandidate, Error};
+use asahi_common::json_string;
 use clap::Parser;
 use std::{
+    borrow::Cow,
     io::{stdin, stdout, Write},
     num::IntErrorKind,
+    path::{Path, PathBuf},
     process::ExitCode,
 };
 
@@ -30,17 +33,42 @@ struct Args {
     )]
     next: bool,
 
+    #[arg(
+        long,
+        conflicts_with_all = &["next", "next_boot"],
+        help = "Set boot volume for next boot until --clear-next is used, which restores the previous default. \
+                Without this, --next only affects a single boot."
+    )]
+    next_persist: bool,
+
     #[arg(
         short,
         long,
-        conflicts_with_all = &["set_boot", "set_boot_macos"],
+        conflicts_with_all = &["set_boot", "set_boot_macos", "next_boot"],
         help = "List boot volume candidates"
     )]
     list_volumes: bool,
 
+    // for shell completion of --set-boot, the output is kept stable rather than readable
+    #[arg(
+        long,
+        hide = true,
+        conflicts_with_all = &["list_volumes", "get_boot", "current_os"],
+        help = "Print the names of the boot volume candidates, one per line"
+    )]
+    list_names: bool,
+
     #[arg(long, value_name = "name_or_index", help = "Set boot volume by name or index")]
     set_boot: Option<String>,
 
+    #[arg(
+        long,
+        value_name = "name_or_index",
+        conflicts_with_all = &["next", "set_boot_macos"],
+        help = "Set boot volume for next boot only by name or index. May be combined with --set-boot to set both at once."
+    )]
+    next_boot: Option<String>,
+
     #[arg(
         long,
         conflicts_with = "set_boot",
@@ -51,11 +79,90 @@ struct Args {
     #[arg(name = "yes", short, long, help = "Do not ask for confirmation")]
     autoconfirm: bool,
 
+    #[arg(long, help = "Print the exact boot-volume value that will be written to nvram")]
+    show_value: bool,
+
     #[arg(long, help = "Get currently selected boot target. May be combined with --next to show the next boot target.")]
     get_boot: bool,
 
-    #[arg(long, help = "Clear the selected next boot target")]
+    #[arg(
+        long,
+        visible_alias = "uuid",
+        requires = "get_boot",
+        help = "Only read nvram and print the volume group UUID of the boot target instead of its name"
+    )]
+    no_scan: bool,
+
+    #[arg(
+        long,
+        requires = "get_boot",
+        conflicts_with = "no_scan",
+        help = "Print the boot target as a JSON object"
+    )]
+    json: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = &["list_volumes", "get_boot"],
+        help = "Print the volume the running system was booted from, unlike --get-boot this doesn't read nvram"
+    )]
+    current_os: bool,
+
+    #[arg(long, help = "Clear the selected next boot target, restoring the default replaced by --next-persist")]
     clear_next: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = &["set_boot", "next_boot", "set_boot_macos", "list_volumes", "get_boot", "clear_next", "next_persist"],
+        help = "Switch the boot target to the other volume when there are exactly two. May be combined with --next."
+    )]
+    toggle: bool,
+
+    #[arg(
+        short,
+        long,
+        action = clap::ArgAction::Count,
+        help = "Report partitions that could not be scanned for boot volumes, repeat for more diagnostics"
+    )]
+    verbose: u8,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "Save the current nvram contents before modifying them, to a temporary file if no path is given"
+    )]
+    backup: Option<Option<PathBuf>>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Keep the scanned boot volume candidates in this file and reuse them while the partition table is unchanged"
+    )]
+    candidates_cache: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "force",
+        conflicts_with_all = &["list_volumes", "list_names", "get_boot", "current_os"],
+        help = "Write on top of this nvram bank instead of the active one, to recover when the firmware keeps reading another bank"
+    )]
+    bank: Option<usize>,
+
+    #[arg(long, help = "Allow options that can leave nvram in a state the firmware doesn't expect")]
+    force: bool,
+
+    #[arg(long, requires = "candidates_cache", help = "Rescan boot volume candidates instead of using the cache")]
+    refresh: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "candidates_cache",
+        help = "Look for boot volumes on every nvme and sd disk, not just the internal one"
+    )]
+    all: bool,
 }
 
 fn error_to_string(e: Error) -> String {
@@ -66,8 +173,17 @@ fn error_to_string(e: Error) -> String {
         Error::SectionTooBig => "Ran out of space on nvram".to_string(),
         Error::ApplyError(e) => format!("Failed to save new nvram contents, try running with sudo? Inner error: {:?}", e),
         Error::NvramReadError(e) => format!("Failed to read nvram contents, try running with sudo? Inner error: {:?}", e),
-        Error::DiskReadError(e) => format!("Failed to collect boot candidates, try running with sudo? Inner error: {:?}", e),
+        Error::DiskReadError(e) if e.kind() == std::io::ErrorKind::PermissionDenied => format!("Failed to open the disk to collect boot candidates, try running with sudo? Inner error: {:?}", e),
+        Error::DiskReadError(e) => format!("Failed to read the disk to collect boot candidates: {:?}", e),
+        Error::NoPartitionTable(e) => format!("The disk has no readable GPT partition table: {}", e),
         Error::VolumeNotFound => "Unable to find specified volume".to_string(),
+        Error::CannotToggle => "Toggling needs exactly two boot volumes, one of which is the current default".to_string(),
+        Error::NoCandidates => "No bootable volumes found on the disk".to_string(),
+        Error::BackupError(e) => format!("Failed to save a backup of the nvram contents: {:?}", e),
+        Error::CacheError(e) => format!("Failed to save the boot candidates cache: {:?}", e),
+        Error::ReadOnlyDevice(writable) => format!("The nvram device is a read-only node, use {} to change the boot target", writable.display()),
+        Error::InvalidBank(bank) => format!("nvram bank {} does not exist or is not valid", bank),
+        Error::RunningOsUnknown => "Unable to tell which volume the running system was booted from, was it started by m1n1 from the Asahi installer?".to_string(),
     }
 }
 
@@ -83,58 +199,82 @@ fn main() -> ExitCode {
 
 fn real_main() -> Result<()> {
     let args = Args::parse();
+    apple_nvram::logger::init(args.verbose);
 
     let device = match args.device {
         Some(ref dev) => dev,
         None => "/dev/mtd/by-name/nvram",
     };
+    log::debug!("using nvram device {}", device);
 
-    if args.list_volumes {
+    if args.list_names {
+        for cand in get_boot_candidates(&args)? {
+            println!("{}", get_vg_name(&cand));
+        }
+    } else if args.list_volumes {
         list_boot_volumes(&args, device)?;
     } else if args.get_boot {
         print_boot_target(&args, device)?;
+    } else if args.current_os {
+        print_current_os(&args)?;
+    } else {
+        let backup = match &args.backup {
+            Some(path) => Some(backup_nvram(device, path.as_deref())?),
+            None => None,
+        };
+        let result = modify_boot_target(&args, device);
+        if let (Err(_), Some(path)) = (&result, backup) {
+            eprintln!("The nvram contents before this change were saved to {}", path.display());
+        }
+        result?;
+    }
+
+    Ok(())
+}
+
+fn backup_nvram(device: &str, path: Option<&Path>) -> Result<PathBuf> {
+    let data = std::fs::read(device).map_err(Error::NvramReadError)?;
+    let path = apple_nvram::write_backup(&data, path).map_err(Error::BackupError)?;
+    eprintln!("Saved current nvram contents to {}", path.display());
+    Ok(path)
+}
+
+fn modify_boot_target(args: &Args, device: &str) -> Result<()> {
+    if args.toggle {
+        let cand = toggle_boot(device, args.next, args.bank)?;
+        let which = if args.next { "next boot target" } else { "boot target" };
+        println!("Set {} to {}", which, get_vg_name(&cand));
+        print_value(args, if args.next { "alt-boot-volume" } else { "boot-volume" }, &cand);
     } else if args.clear_next {
-        if clear_next_boot(device)? {
+        if clear_next_boot(device, args.bank)? {
             println!("Cleared next boot target");
         } else {
             println!("Next boot target was already empty");
         }
-    } else if let Some(spec) = &args.set_boot {
-        let cands = get_boot_candidates()?;
-        let lc_name = spec.to_lowercase();
-        for cand in &cands {
-            if cand.volumes.iter().any(|n| n.name.to_lowercase() == lc_name) {
-                set_boot_volume_by_ref(device, &cand, &args, false)?;
-                return Ok(());
-            }
-        }
-        if let Ok(idx) = spec.parse::<usize>() {
-            let cand = cands
-                .into_iter()
-                .nth(idx - 1)
-                .ok_or(Error::OutOfRange)?;
-            set_boot_volume_by_ref(device, &cand, &args, false)?;
+    } else if args.set_boot.is_some() || args.next_boot.is_some() {
+        let cands = get_boot_candidates(args)?;
+        let default_cand = args
+            .set_boot
+            .as_ref()
+            .map(|spec| find_boot_candidate(&cands, spec))
+            .transpose()?;
+        let next_cand = args
+            .next_boot
+            .as_ref()
+            .map(|spec| find_boot_candidate(&cands, spec))
+            .transpose()?;
+        if let (true, Some(cand)) = (args.next_persist, default_cand) {
+            set_boot_volume_by_ref(device, cand, args, false)?;
+        } else if args.next {
+            set_boot_volumes_by_ref(device, None, default_cand, args, false)?;
         } else {
-            return Err(Error::VolumeNotFound);
+            set_boot_volumes_by_ref(device, default_cand, next_cand, args, false)?;
         }
     } else if args.set_boot_macos {
-        let cands = get_boot_candidates()?;
-        let macos_cands: Vec<_> = cands
-            .iter()
-            .filter(|c| {
-                c.volumes
-                    .first()
-                    .map(|n| n.name.starts_with("Macintosh"))
-                    .unwrap_or(false)
-            })
-            .collect();
-        if macos_cands.len() == 1 {
-            set_boot_volume_by_ref(device, &macos_cands[0], &args, false)?;
-        } else {
-            return Err(Error::Ambiguous);
-        }
+        let cands = get_boot_candidates(args)?;
+        set_boot_volume_by_ref(device, find_macos_candidate(&cands)?, args, false)?;
     } else {
-        interactive_main(&args, device)?;
+        interactive_main(args, device)?;
     }
 
     Ok(())
@@ -148,68 +288,195 @@ fn confirm() -> bool {
     input.trim().to_lowercase() == "y"
 }
 
-fn get_vg_name(vg: &[Volume]) -> &str {
-    for v in vg {
-        if v.is_system {
-            return &v.name;
+fn get_boot_candidates(args: &Args) -> Result<Vec<BootCandidate>> {
+    let mut errors = Vec::new();
+    let cands = match &args.candidates_cache {
+        _ if args.all => get_boot_candidates_all_disks(&mut errors)?,
+        Some(cache) => get_boot_candidates_cached(cache, args.refresh, &mut errors)?,
+        None => get_boot_candidates_with_errors(&mut errors)?,
+    };
+    // always explain an empty list, otherwise only report when asked to
+    log::debug!("found {} boot candidates", cands.len());
+    if args.verbose > 0 || cands.is_empty() {
+        for e in &errors {
+            eprintln!("Warning: {}", e);
         }
     }
-    &vg[0].name
+    Ok(cands)
+}
+
+// a candidate read back from an old or edited cache may have no volumes, it's shown by its uuid
+fn get_vg_name(cand: &BootCandidate) -> Cow<'_, str> {
+    match cand.volumes.iter().find(|v| v.is_system).or(cand.volumes.first()) {
+        Some(v) => Cow::Borrowed(&v.name),
+        None => Cow::Owned(cand.vg_uuid.to_string()),
+    }
 }
 
 fn print_boot_target(args: &Args, device: &str) -> Result<()> {
-    let cands = get_boot_candidates()?;
     let default_cand = get_boot_volume(device, args.next)?;
-    for cand in cands {
-        if (cand.part_uuid == default_cand.part_uuid) && (cand.vg_uuid == default_cand.vg_uuid) {
-            println!("{}", get_vg_name(&cand.volumes));
-            return Ok(());
-        }
+    if args.no_scan {
+        println!("{}", default_cand.vg_uuid);
+        return Ok(());
     }
-    println!("No boot target set");
+    let cands = get_boot_candidates(args)?;
+    let name = cands
+        .iter()
+        .find(|cand| cand.matches_target(&default_cand))
+        .map(|cand| get_vg_name(cand));
+    if args.json {
+        println!(
+            "{{\"vg_uuid\": \"{}\", \"part_uuid\": \"{}\", \"name\": {}, \"found\": {}}}",
+            default_cand.vg_uuid,
+            default_cand.part_uuid,
+            name.as_deref().map(json_string).unwrap_or_else(|| "null".to_owned()),
+            name.is_some()
+        );
+    } else if let Some(name) = name {
+        println!("{}", name);
+    } else {
+        println!("No boot target set");
+    }
+    Ok(())
+}
+
+fn print_current_os(args: &Args) -> Result<()> {
+    let part_uuid = get_running_part_uuid()?;
+    let cands = get_boot_candidates(args)?;
+    let cand = cands
+        .iter()
+        .find(|cand| cand.part_uuid == part_uuid)
+        .ok_or(Error::RunningOsUnknown)?;
+    println!("{}", get_vg_name(cand));
     Ok(())
 }
 
 fn list_boot_volumes(args: &Args, device: &str) -> Result<Vec<BootCandidate>> {
-    let cands = get_boot_candidates()?;
+    let cands = get_boot_candidates(args)?;
     let default_cand = get_boot_volume(device, args.next)?;
     let mut is_default: &str;
     for (i, cand) in cands.iter().enumerate() {
-        if (cand.part_uuid == default_cand.part_uuid) && (cand.vg_uuid == default_cand.vg_uuid) {
+        if *cand == default_cand {
             is_default = "*";
         } else {
             is_default = " ";
         }
-        println!("{}{}) {}", is_default, i + 1, get_vg_name(&cand.volumes));
+        match &cand.source {
+            // identically named volumes on different disks are told apart by where they are
+            Some(source) if args.verbose > 0 => println!(
+                "{}{}) {} ({})",
+                is_default,
+                i + 1,
+                get_vg_name(cand),
+                source.display()
+            ),
+            _ => println!("{}{}) {}", is_default, i + 1, get_vg_name(cand)),
+        }
     }
     Ok(cands)
 }
 
+fn find_macos_candidate(cands: &[BootCandidate]) -> Result<&BootCandidate> {
+    if cands.is_empty() {
+        return Err(Error::NoCandidates);
+    }
+    let macos_cands: Vec<_> = cands
+        .iter()
+        .filter(|c| {
+            c.volumes
+                .first()
+                .map(|n| n.name.starts_with("Macintosh"))
+                .unwrap_or(false)
+        })
+        .collect();
+    match macos_cands[..] {
+        [cand] => Ok(cand),
+        _ => Err(Error::Ambiguous),
+    }
+}
+
+fn find_boot_candidate<'a>(cands: &'a [BootCandidate], spec: &str) -> Result<&'a BootCandidate> {
+    if cands.is_empty() {
+        return Err(Error::NoCandidates);
+    }
+    let lc_name = spec.to_lowercase();
+    for cand in cands {
+        if cand.volumes.iter().any(|n| n.name.to_lowercase() == lc_name) {
+            return Ok(cand);
+        }
+    }
+    if let Ok(idx) = spec.parse::<usize>() {
+        idx.checked_sub(1)
+            .and_then(|i| cands.get(i))
+            .ok_or(Error::OutOfRange)
+    } else {
+        Err(Error::VolumeNotFound)
+    }
+}
+
 fn set_boot_volume_by_ref(
     device: &str,
     cand: &BootCandidate,
     args: &Args,
     interactive: bool,
 ) -> Result<()> {
-    if !interactive {
-        let as_what = if !args.next {
-            "default boot target"
-        } else {
-            "boot target for next boot only"
-        };
-        println!("Will set volume {} as the {}", get_vg_name(&cand.volumes), as_what);
-    }
-    if !args.autoconfirm && !interactive {
-        if !confirm() {
+    if args.next_persist {
+        if !interactive {
+            println!("Will set volume {} as the boot target until --clear-next is used", get_vg_name(cand));
+        }
+        print_value(args, "boot-volume", cand);
+        if !args.autoconfirm && !interactive && !confirm() {
             return Ok(());
         }
+        set_next_boot_persistent(device, cand, args.bank)
+    } else if args.next {
+        set_boot_volumes_by_ref(device, None, Some(cand), args, interactive)
+    } else {
+        set_boot_volumes_by_ref(device, Some(cand), None, args, interactive)
+    }
+}
+
+fn set_boot_volumes_by_ref(
+    device: &str,
+    default: Option<&BootCandidate>,
+    next: Option<&BootCandidate>,
+    args: &Args,
+    interactive: bool,
+) -> Result<()> {
+    if !interactive {
+        if let Some(cand) = default {
+            println!("Will set volume {} as the default boot target", get_vg_name(cand));
+        }
+        if let Some(cand) = next {
+            println!("Will set volume {} as the boot target for next boot only", get_vg_name(cand));
+        }
+    }
+    if let Some(cand) = default {
+        print_value(args, "boot-volume", cand);
+    }
+    if let Some(cand) = next {
+        print_value(args, "alt-boot-volume", cand);
     }
-    set_boot_volume(device, cand, args.next)?;
+    if !args.autoconfirm && !interactive && !confirm() {
+        return Ok(());
+    }
+    set_boot_volumes(device, default, next, args.bank)?;
     Ok(())
 }
 
+// in the form asahi-nvram write takes, so it can be copied to set the target by hand
+fn print_value(args: &Args, var: &str, cand: &BootCandidate) {
+    if args.show_value {
+        println!("  system:{}={}", var, boot_volume_value(cand));
+    }
+}
+
 fn interactive_main(args: &Args, device: &str) -> Result<()> {
     let cands = list_boot_volumes(args, device)?;
+    // nothing could ever be selected, don't get stuck at the prompt
+    if cands.is_empty() {
+        return Err(Error::NoCandidates);
+    }
     println!("\nEnter a number to select a boot volume:");
 
     let mut input = String::new();
@@ -232,3 +499,51 @@ fn interactive_main(args: &Args, device: &str) -> Result<()> {
 
     set_boot_volume_by_ref(device, &cands[index], args, true)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asahi_bless::Volume;
+    use uuid::Uuid;
+
+    fn cand(n: u128, name: &str) -> BootCandidate {
+        BootCandidate {
+            part_uuid: Uuid::from_u128(n),
+            vg_uuid: Uuid::from_u128(n + 1),
+            volumes: vec![Volume {
+                name: name.to_owned(),
+                is_system: true,
+            }],
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_no_candidates() {
+        assert!(matches!(find_macos_candidate(&[]), Err(Error::NoCandidates)));
+        assert!(matches!(find_boot_candidate(&[], "1"), Err(Error::NoCandidates)));
+        assert!(matches!(find_boot_candidate(&[], "Macintosh HD"), Err(Error::NoCandidates)));
+
+        let cands = [cand(1, "Macintosh HD"), cand(3, "Asahi Linux")];
+        assert!(find_macos_candidate(&cands).unwrap().matches_target(&cands[0]));
+        assert!(matches!(find_macos_candidate(&cands[1..]), Err(Error::Ambiguous)));
+        assert!(matches!(find_boot_candidate(&cands, "0"), Err(Error::OutOfRange)));
+        assert!(matches!(find_boot_candidate(&cands, "3"), Err(Error::OutOfRange)));
+        assert!(find_boot_candidate(&cands, "2").unwrap().matches_target(&cands[1]));
+    }
+
+    #[test]
+    fn test_vg_name() {
+        let mut c = cand(1, "Macintosh HD");
+        c.volumes[0].is_system = false;
+        c.volumes.push(Volume {
+            name: "Macintosh HD - Data".to_owned(),
+            is_system: false,
+        });
+        assert_eq!(get_vg_name(&c), "Macintosh HD");
+        c.volumes[1].is_system = true;
+        assert_eq!(get_vg_name(&c), "Macintosh HD - Data");
+        c.volumes.clear();
+        assert_eq!(get_vg_name(&c), c.vg_uuid.to_string());
+    }
+}
@@ -1,7 +1,6 @@
 use std::time::Duration;
 
-// TODO: not architecture independent but correct for arm/ppc/x86
-const SIGUSR1: i32 = 10;
+use nix::sys::signal::Signal;
 
 // busctl call org.freedesktop.systemd1 /org/freedesktop/systemd1 org.freedesktop.systemd1.Manager KillUnit ssi bluetooth.service main 10
 
@@ -18,7 +17,7 @@ pub fn systemd_reload_bt_config() -> Result<(), Box<dyn std::error::Error>> {
     let r: Result<(), _> = systemd1.method_call(
         "org.freedesktop.systemd1.Manager",
         "KillUnit",
-        ("bluetooth.service", "main", SIGUSR1),
+        ("bluetooth.service", "main", Signal::SIGUSR1 as i32),
     );
     r?;
     Ok(())
@@ -28,6 +27,7 @@ fn bluez_connect_device(
     con: &dbus::blocking::Connection,
     adapter_path: &str,
     mac: &[u8; 6],
+    timeout: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let dev_mac = format!(
         "{:02X}_{:02X}_{:02X}_{:02X}_{:02X}_{:02X}",
@@ -36,7 +36,7 @@ fn bluez_connect_device(
     let dev_path = format!("{}/dev_{}", adapter_path, dev_mac);
     println!("connect BT dev {}", dev_path);
 
-    let device1 = con.with_proxy("org.bluez", dev_path, Duration::from_secs(10));
+    let device1 = con.with_proxy("org.bluez", dev_path, timeout);
 
     // busctl call org.bluez /org/bluez/hci0/dev_$BTADDR org.bluez.Device1 Connect
     let r: Result<(), _> = device1.method_call("org.bluez.Device1", "Connect", ());
@@ -44,7 +44,14 @@ fn bluez_connect_device(
     Ok(())
 }
 
-pub fn bluez_connect(info: &crate::BtInfo) -> Result<(), Box<dyn std::error::Error>> {
+// addresses of the devices that didn't connect, with the reason
+pub type FailedDevices = Vec<([u8; 6], String)>;
+
+// only failing to talk to bluez at all is an error, single devices end up in FailedDevices
+pub fn bluez_connect(
+    info: &crate::BtInfo,
+    timeout: Duration,
+) -> Result<FailedDevices, Box<dyn std::error::Error>> {
     let con = dbus::blocking::Connection::new_system()?;
 
     let adapter_mac = format!(
@@ -71,11 +78,15 @@ pub fn bluez_connect(info: &crate::BtInfo) -> Result<(), Box<dyn std::error::Err
         let bt_addr: String = adapter1.get("org.bluez.Adapter1", "Address")?;
 
         if bt_addr.eq(adapter_mac.as_str()) {
+            // a device that is switched off shouldn't keep the others from connecting
+            let mut failed = Vec::new();
             for dev in &info.devices {
-                bluez_connect_device(&con, &adapter_path, &dev.mac)?;
+                if let Err(e) = bluez_connect_device(&con, &adapter_path, &dev.mac, timeout) {
+                    failed.push((dev.mac, e.to_string()));
+                }
             }
-            return Ok(());
+            return Ok(failed);
         }
     }
-    Ok(())
+    Ok(Vec::new())
 }
@@ -0,0 +1,513 @@
+/* SPDX-License-Identifier: MIT */
+
+use std::{fmt::Debug, fs, io, path::Path, time::Duration};
+
+use apple_nvram::{Nvram, VarType, Variable};
+use asahi_common::{json_string, write_ini, FileStatus};
+
+use ini::Ini;
+
+#[cfg(feature = "dbus")]
+pub mod dbus;
+
+#[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
+pub enum Error {
+    Parse,
+    SectionTooBig,
+    ApplyError(std::io::Error),
+    VariableNotFound,
+    DeviceNotFound,
+    InvalidMac,
+    FileIO,
+    BluezConfigDirNotFound,
+    SliceError,
+    DbusSystemd,
+    DbusBluez,
+}
+
+impl From<apple_nvram::Error> for Error {
+    fn from(e: apple_nvram::Error) -> Self {
+        match e {
+            apple_nvram::Error::ParseError => Error::Parse,
+            apple_nvram::Error::ChecksumMismatch { .. } => Error::Parse,
+            apple_nvram::Error::InvalidBank => Error::Parse,
+            apple_nvram::Error::UnknownFormat { .. } => Error::Parse,
+            apple_nvram::Error::UnsupportedGuid => Error::Parse,
+            apple_nvram::Error::Unsupported => Error::Parse,
+            apple_nvram::Error::Blank => Error::Parse,
+            apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
+            apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::EraseError(e) => Error::ApplyError(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(_e: io::Error) -> Self {
+        Error::FileIO
+    }
+}
+
+impl From<std::array::TryFromSliceError> for Error {
+    fn from(_e: std::array::TryFromSliceError) -> Self {
+        Error::SliceError
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub const BT_VAR: &str = "BluetoothUHEDevices";
+
+pub struct BtDevice {
+    pub mac: [u8; 6],
+    pub class: u16,
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub pairing_key: [u8; 16],
+}
+
+impl BtDevice {
+    // an all zero key is a placeholder, there is nothing bluez could authenticate with
+    pub fn has_pairing_key(&self) -> bool {
+        self.pairing_key != [0; 16]
+    }
+}
+
+pub struct BtInfo {
+    pub mac: [u8; 6],
+    pub devices: Vec<BtDevice>,
+}
+
+// splits off the next len bytes, firmware data that ends early is a parse error instead of a panic
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(Error::Parse);
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn read_le_u16(input: &mut &[u8]) -> Result<u16> {
+    Ok(u16::from_le_bytes(
+        take(input, std::mem::size_of::<u16>())?.try_into()?,
+    ))
+}
+
+fn parse_bt_device(input: &mut &[u8]) -> Result<BtDevice> {
+    // parse MAC
+    let mac: [u8; 6] = take(input, 6)?.try_into()?;
+
+    // class of device, bluez picks the icon from this via Class= in the info file
+    let class = read_le_u16(input)?;
+
+    // skip 2 bytes
+    take(input, 2)?;
+
+    // parse device name (u16_le length + \0 terminated utf-8 string)
+    let name_len = read_le_u16(input)? as usize;
+    let name_bytes = take(input, name_len)?;
+    let name =
+        String::from_utf8_lossy(name_bytes.strip_suffix(&[0]).unwrap_or(name_bytes)).to_string();
+
+    // parse pairing key
+    let key: [u8; 16] = take(input, 16)?.try_into()?;
+
+    // parse product / vendor id
+    let product_id = read_le_u16(input)?;
+    let vendor_id = read_le_u16(input)?;
+
+    // skip 2 unknown trailing bytes
+    take(input, 2)?;
+
+    Ok(BtDevice {
+        mac,
+        class,
+        name,
+        vendor_id,
+        product_id,
+        pairing_key: key,
+    })
+}
+
+pub fn parse_bt_info(var: &dyn Variable) -> Result<BtInfo> {
+    parse_bt_data(&var.value())
+}
+
+pub fn read_bt_devices(nv: &dyn Nvram) -> Result<BtInfo> {
+    let data = nv
+        .get(VarType::System, BT_VAR.as_bytes())
+        .ok_or(Error::VariableNotFound)?;
+    parse_bt_data(&data)
+}
+
+pub fn parse_bt_data(data: &[u8]) -> Result<BtInfo> {
+    // left empty when Bluetooth was never used under macos
+    if data.is_empty() {
+        return Ok(BtInfo {
+            mac: [0; 6],
+            devices: Vec::new(),
+        });
+    }
+    // adapter mac, device count and a byte that has always been 4
+    if data.len() < 8 || data[7] != 0x04 {
+        return Err(Error::Parse);
+    }
+    let adapter_mac: [u8; 6] = data[0..6].try_into()?;
+    let num_devices = data[6];
+
+    let mut dev_data = &data[8..];
+
+    let mut devices: Vec<BtDevice> = Vec::new();
+    for _n in 0..num_devices {
+        devices.push(parse_bt_device(&mut dev_data)?);
+    }
+
+    Ok(BtInfo {
+        mac: adapter_mac,
+        devices,
+    })
+}
+
+// the value with every pairing key zeroed, so a dump can be shared. names and addresses are kept
+pub fn scrub_bt_data(data: &[u8]) -> Result<Vec<u8>> {
+    let info = parse_bt_data(data)?;
+    let mut out = data.to_vec();
+    let mut rest = data.get(8..).unwrap_or_default();
+    for _ in &info.devices {
+        parse_bt_device(&mut rest)?;
+        // the key is followed by the product and vendor ids and 2 unknown bytes
+        let key_end = data.len() - rest.len() - 6;
+        out[key_end - 16..key_end].fill(0);
+    }
+    Ok(out)
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> Result<String> {
+    Ok(mac
+        .iter()
+        .map(|x| format!("{x:02X}"))
+        .collect::<Vec<String>>()
+        .join(":"))
+}
+
+// inverse of format_mac, also takes lower case and the - separated form windows uses
+pub fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let bytes = mac
+        .split([':', '-'])
+        .map(|b| match b.len() {
+            2 if b.chars().all(|c| c.is_ascii_hexdigit()) => {
+                u8::from_str_radix(b, 16).map_err(|_| Error::InvalidMac)
+            }
+            _ => Err(Error::InvalidMac),
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    bytes.try_into().map_err(|_| Error::InvalidMac)
+}
+
+fn format_key(key: &[u8; 16]) -> Result<String> {
+    Ok(key.iter().map(|x| format!("{x:02X}")).rev().collect())
+}
+
+// pairing keys are only printed when asked for, to keep them out of scrollback and logs
+pub fn print_btkeys(var: &dyn Variable, show_secrets: bool) -> Result<()> {
+    let info = parse_bt_info(var)?;
+
+    for dev in info.devices {
+        let key = match dev.has_pairing_key() {
+            true if show_secrets => format!(", key {}", format_key(&dev.pairing_key)?),
+            true => String::new(),
+            false => ", no pairing key".to_owned(),
+        };
+        println!(
+            "ID {:04x}:{:04x} {} ({}){}",
+            dev.vendor_id,
+            dev.product_id,
+            dev.name,
+            format_mac(&dev.mac)?,
+            key
+        );
+    }
+    Ok(())
+}
+
+// one object per device. the record only has the BR/EDR link key, there are no LE keys to report
+pub fn format_btkeys_json(info: &BtInfo, show_secrets: bool) -> Result<String> {
+    let mut devices = Vec::new();
+    for dev in &info.devices {
+        let key = if show_secrets && dev.has_pairing_key() {
+            format!(", \"pairing_key\": \"{}\"", format_key(&dev.pairing_key)?)
+        } else {
+            String::new()
+        };
+        devices.push(format!(
+            "  {{\"mac\": \"{}\", \"name\": {}, \"class\": {}, \"vendor_id\": {}, \"product_id\": {}, \"has_pairing_key\": {}{}}}",
+            format_mac(&dev.mac)?,
+            json_string(&dev.name),
+            dev.class,
+            dev.vendor_id,
+            dev.product_id,
+            dev.has_pairing_key(),
+            key
+        ));
+    }
+    if devices.is_empty() {
+        return Ok("[]".to_owned());
+    }
+    Ok(format!("[\n{}\n]", devices.join(",\n")))
+}
+
+// returns how many devices were written, ones that already have an info file are left alone
+pub fn sync_btkeys(
+    var: &dyn Variable,
+    config: &String,
+    mac: Option<[u8; 6]>,
+    overwrite: bool,
+    // how long to wait for each device to connect, None to leave bluetoothd alone
+    connect_timeout: Option<Duration>,
+) -> Result<usize> {
+    let config_path = Path::new(config);
+
+    if !config_path.is_dir() {
+        return Err(Error::BluezConfigDirNotFound);
+    }
+
+    let mut info = parse_bt_info(var)?;
+    if let Some(mac) = mac {
+        info.devices.retain(|dev| dev.mac == mac);
+        if info.devices.is_empty() {
+            return Err(Error::DeviceNotFound);
+        }
+    }
+    let added_devs = write_btkeys(&info, config_path, overwrite)?;
+    if added_devs > 0 {
+        match connect_timeout {
+            Some(timeout) => reload_bluez(&info, timeout)?,
+            None => println!("Restart bluetooth to load the new devices"),
+        }
+    }
+    Ok(added_devs)
+}
+
+#[cfg(feature = "dbus")]
+fn reload_bluez(info: &BtInfo, connect_timeout: Duration) -> Result<()> {
+    if let Err(e) = dbus::systemd_reload_bt_config() {
+        println!("Failed to reload bluetoothd config {}", e);
+        return Err(Error::DbusSystemd);
+    }
+    // sleep 500 ms to let bluetoothd reload its config
+    std::thread::sleep(Duration::from_millis(500));
+    let failed = match dbus::bluez_connect(info, connect_timeout) {
+        Ok(failed) => failed,
+        Err(e) => {
+            println!("Failed to connect bluetooth devices {}", e);
+            return Err(Error::DbusBluez);
+        }
+    };
+    // the info files are written either way, these connect once they're switched on
+    for (mac, e) in &failed {
+        let name = info.devices.iter().find(|dev| dev.mac == *mac);
+        println!(
+            "Warning: couldn't connect {} ({}): {}",
+            name.map_or("", |dev| dev.name.as_str()),
+            format_mac(mac)?,
+            e
+        );
+    }
+    if !failed.is_empty() {
+        println!(
+            "{} of {} devices didn't connect",
+            failed.len(),
+            info.devices.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus"))]
+fn reload_bluez(_info: &BtInfo, _connect_timeout: Duration) -> Result<()> {
+    println!("Built without dbus support, restart bluetooth to load the new devices");
+    Ok(())
+}
+
+// same layout as sync, but anywhere and without telling bluez, so the keys can be reviewed and
+// copied to /var/lib/bluetooth by hand
+pub fn export_btkeys(var: &dyn Variable, out: &Path) -> Result<()> {
+    let info = parse_bt_info(var)?;
+    fs::create_dir_all(out)?;
+    write_btkeys(&info, out, false)?;
+    Ok(())
+}
+
+// writes <base>/<adapter>/<device>/info for every device, existing files are only replaced with
+// overwrite. returns how many were created or updated
+pub fn write_btkeys(info: &BtInfo, base: &Path, overwrite: bool) -> Result<usize> {
+    // there's no adapter address to make a directory for
+    if info.devices.is_empty() {
+        return Ok(0);
+    }
+    let adapter_path = base.join(format_mac(&info.mac)?);
+
+    if !adapter_path.is_dir() {
+        fs::create_dir(adapter_path.clone())?;
+    }
+    let mut added_devs = 0;
+
+    for dev in &info.devices {
+        let dev_path = adapter_path.join(format_mac(&dev.mac)?);
+
+        if !dev_path.is_dir() {
+            fs::create_dir(dev_path.clone())?;
+        }
+
+        let info_file = dev_path.as_path().join("info");
+
+        let mut info = Ini::new();
+
+        // BluetoothUHEDevices only holds BR/EDR devices with a link key, no LE records. bluez
+        // treats an info file without AddressType as BR/EDR, so none is written.
+        info.with_section(Some("General"))
+            .set("Name", dev.name.clone())
+            .set("Class", format!("{:#08X}", dev.class))
+            .set("Trusted", "true")
+            .set("Blocked", "false")
+            .set("WakeAllowed", "true");
+        if dev.has_pairing_key() {
+            info.with_section(Some("LinkKey"))
+                .set("Key", format_key(&dev.pairing_key)?);
+        } else {
+            log::warn!(
+                "{} ({}) has no pairing key, it will have to be paired again",
+                dev.name,
+                format_mac(&dev.mac)?
+            );
+        }
+        info.with_section(Some("DeviceID"))
+            .set("Vendor", format!("{}", dev.vendor_id))
+            .set("Product", format!("{}", dev.product_id));
+        let status = write_ini(&info, &info_file, overwrite)?;
+
+        println!("{}: {}", format_mac(&dev.mac)?, status);
+        if matches!(status, FileStatus::Created | FileStatus::Updated) {
+            added_devs += 1;
+        }
+    }
+    Ok(added_devs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apple_nvram::nvram_parse;
+
+    fn device(mac: [u8; 6], name: &str, key: [u8; 16]) -> Vec<u8> {
+        let mut dev = mac.to_vec();
+        dev.extend_from_slice(&0x2540u16.to_le_bytes());
+        dev.extend_from_slice(&[0; 2]);
+        dev.extend_from_slice(&(name.len() as u16 + 1).to_le_bytes());
+        dev.extend_from_slice(name.as_bytes());
+        dev.push(0);
+        dev.extend_from_slice(&key);
+        dev.extend_from_slice(&0x0267u16.to_le_bytes());
+        dev.extend_from_slice(&0x004cu16.to_le_bytes());
+        dev.extend_from_slice(&[0; 2]);
+        dev
+    }
+
+    fn parse_from_image(value: Vec<u8>) -> Result<BtInfo> {
+        let image =
+            apple_nvram::v3::Nvram::with_variable(VarType::System, b"BluetoothUHEDevices", &value)?;
+        let nv = nvram_parse(&image)?;
+        read_bt_devices(nv.as_ref())
+    }
+
+    // more than two devices so a fixed device count is caught
+    #[test]
+    fn test_parse_bt_info_from_image() -> Result<()> {
+        let mut value = vec![0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 3, 0x04];
+        value.extend(device([1, 2, 3, 4, 5, 6], "Keyboard", [0x11; 16]));
+        value.extend(device([2, 3, 4, 5, 6, 7], "Mouse", [0x22; 16]));
+        value.extend(device([3, 4, 5, 6, 7, 8], "Headphones", [0; 16]));
+
+        let info = parse_from_image(value)?;
+        assert_eq!(format_mac(&info.mac)?, "A1:A2:A3:A4:A5:A6");
+        assert_eq!(info.devices.len(), 3);
+        assert_eq!(info.devices[0].name, "Keyboard");
+        assert_eq!(info.devices[0].class, 0x2540);
+        assert_eq!(info.devices[0].vendor_id, 0x004c);
+        assert_eq!(info.devices[0].product_id, 0x0267);
+        assert_eq!(format_mac(&info.devices[1].mac)?, "02:03:04:05:06:07");
+        assert_eq!(info.devices[1].pairing_key, [0x22; 16]);
+        assert_eq!(info.devices[2].name, "Headphones");
+        assert!(!info.devices[2].has_pairing_key());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bt_info_short() -> Result<()> {
+        assert!(parse_from_image(Vec::new())?.devices.is_empty());
+        assert!(matches!(
+            parse_from_image(vec![0xa1, 0xa2, 0xa3]),
+            Err(Error::Parse)
+        ));
+        let mut value = vec![0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 1, 0x04];
+        value.extend(&device([1, 2, 3, 4, 5, 6], "Keyboard", [0x11; 16])[..20]);
+        assert!(matches!(parse_from_image(value), Err(Error::Parse)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_btkeys_json() -> Result<()> {
+        let mut value = vec![0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 2, 0x04];
+        value.extend(device([1, 2, 3, 4, 5, 6], "Bob's \"Keyboard\"", [0x11; 16]));
+        value.extend(device([2, 3, 4, 5, 6, 7], "Mouse", [0; 16]));
+        let info = parse_bt_data(&value)?;
+
+        let json = format_btkeys_json(&info, false)?;
+        assert_eq!(
+            json,
+            "[\n  {\"mac\": \"01:02:03:04:05:06\", \"name\": \"Bob's \\\"Keyboard\\\"\", \"class\": 9536, \
+             \"vendor_id\": 76, \"product_id\": 615, \"has_pairing_key\": true},\n  \
+             {\"mac\": \"02:03:04:05:06:07\", \"name\": \"Mouse\", \"class\": 9536, \"vendor_id\": 76, \
+             \"product_id\": 615, \"has_pairing_key\": false}\n]"
+        );
+        let json = format_btkeys_json(&info, true)?;
+        assert!(json.contains(&format!("\"pairing_key\": \"{}\"", "11".repeat(16))));
+        assert_eq!(json.matches("\"pairing_key\":").count(), 1);
+        assert_eq!(format_btkeys_json(&parse_bt_data(&[])?, true)?, "[]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrub_bt_data() -> Result<()> {
+        let mut value = vec![0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 2, 0x04];
+        value.extend(device([1, 2, 3, 4, 5, 6], "Keyboard", [0x11; 16]));
+        value.extend(device([2, 3, 4, 5, 6, 7], "Mouse", [0x22; 16]));
+        let scrubbed = scrub_bt_data(&value)?;
+        assert_eq!(scrubbed.len(), value.len());
+        let info = parse_bt_data(&scrubbed)?;
+        assert_eq!(info.devices.len(), 2);
+        assert_eq!(info.devices[1].name, "Mouse");
+        assert_eq!(info.devices[1].mac, [2, 3, 4, 5, 6, 7]);
+        assert!(info.devices.iter().all(|dev| !dev.has_pairing_key()));
+        assert_eq!(scrub_bt_data(&[])?, []);
+        assert!(scrub_bt_data(&value[..value.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mac() -> Result<()> {
+        let mac = [0xAA, 0xBB, 0xCC, 0x01, 0x02, 0x03];
+        assert_eq!(parse_mac(&format_mac(&mac)?)?, mac);
+        assert!(parse_mac("AA:BB:CC:01:02").is_err());
+        assert!(parse_mac("AA:BB:CC:01:02:3").is_err());
+        assert!(parse_mac("AA:BB:CC:01:02:GG").is_err());
+        assert!(parse_mac("AA:BB:CC:01:02:+3").is_err());
+        assert!(parse_mac("AA:BB:CC:01:02:03:04").is_err());
+        assert_eq!(parse_mac("aa-bb-cc-01-02-03")?, mac);
+        assert_eq!(parse_mac("aA:Bb:cc:01:02:03")?, mac);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+/* SPDX-License-Identifier: MIT */
+
+use std::{
+    fmt::{Display, Formatter},
+    fs, io,
+    path::Path,
+};
+
+use ini::Ini;
+
+#[derive(Debug, PartialEq)]
+pub enum FileStatus {
+    Created,
+    Updated,
+    Unchanged,
+    // differs from nvram, but overwriting wasn't asked for
+    Kept,
+}
+
+impl Display for FileStatus {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            FileStatus::Created => write!(f, "created"),
+            FileStatus::Updated => write!(f, "updated"),
+            FileStatus::Unchanged => write!(f, "unchanged"),
+            FileStatus::Kept => write!(f, "differs, kept (use --overwrite to replace)"),
+        }
+    }
+}
+
+// only writes the file if it changed, one that differs is only replaced with overwrite
+pub fn write_ini(ini: &Ini, path: &Path, overwrite: bool) -> io::Result<FileStatus> {
+    let mut new = Vec::new();
+    ini.write_to(&mut new)?;
+    let status = match fs::read(path) {
+        Ok(old) if old == new => return Ok(FileStatus::Unchanged),
+        Ok(_) if !overwrite => return Ok(FileStatus::Kept),
+        Ok(_) => FileStatus::Updated,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => FileStatus::Created,
+        Err(e) => return Err(e),
+    };
+    fs::write(path, new)?;
+    Ok(status)
+}
+
+// a quoted json string, for the hand written --json output of the tools
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_ini() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("asahi-common-test-{}", std::process::id()));
+        let mut ini = Ini::new();
+        ini.with_section(Some("General")).set("Name", "Keyboard");
+        assert_eq!(write_ini(&ini, &path, false)?, FileStatus::Created);
+        assert_eq!(write_ini(&ini, &path, false)?, FileStatus::Unchanged);
+
+        ini.with_section(Some("General")).set("Name", "Mouse");
+        assert_eq!(write_ini(&ini, &path, false)?, FileStatus::Kept);
+        assert_eq!(write_ini(&ini, &path, true)?, FileStatus::Updated);
+        assert_eq!(write_ini(&ini, &path, true)?, FileStatus::Unchanged);
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: MIT
+// structured interpretations of well known variables, used by `read --decode`.
+// a decoder returns None if the value doesn't have the expected layout.
+
+type Decoder = fn(&[u8]) -> Option<String>;
+
+const DECODERS: &[(&str, Decoder)] = &[
+    ("boot-volume", decode_boot_volume),
+    ("alt-boot-volume", decode_boot_volume),
+    ("asahi-bless-prev-boot-volume", decode_boot_volume),
+    ("backlight-level", decode_le_int),
+    ("SystemAudioVolume", decode_le_int),
+    ("BluetoothUHEDevices", decode_bt_devices),
+    ("preferred-networks", decode_wlan_networks),
+];
+
+pub fn decode(key: &[u8], value: &[u8]) -> Option<String> {
+    let (_, decoder) = DECODERS.iter().find(|(k, _)| k.as_bytes() == key)?;
+    decoder(value)
+}
+
+fn decode_boot_volume(value: &[u8]) -> Option<String> {
+    let value = std::str::from_utf8(value).ok()?;
+    let [part_type, part_uuid, vg_uuid]: [&str; 3] =
+        value.split(':').collect::<Vec<_>>().try_into().ok()?;
+    Some(format!(
+        "partition type {}, partition {}, volume group {}",
+        part_type, part_uuid, vg_uuid
+    ))
+}
+
+fn decode_le_int(value: &[u8]) -> Option<String> {
+    if value.is_empty() || value.len() > 8 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes[..value.len()].copy_from_slice(value);
+    Some(u64::from_le_bytes(bytes).to_string())
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (ret, rest) = input.split_at(len);
+    *input = rest;
+    Some(ret)
+}
+
+fn take_le_u16(input: &mut &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes(take(input, 2)?.try_into().unwrap()))
+}
+
+fn format_mac(mac: &[u8]) -> String {
+    mac.iter()
+        .map(|x| format!("{x:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// same layout as parsed by asahi-btsync, pairing keys are left out
+fn decode_bt_devices(mut value: &[u8]) -> Option<String> {
+    let input = &mut value;
+    let adapter_mac = take(input, 6)?;
+    let count = take(input, 2)?[0];
+    let mut out = format!("adapter {}, {} devices", format_mac(adapter_mac), count);
+    for _ in 0..count {
+        let mac = take(input, 6)?;
+        let class = take_le_u16(input)?;
+        take(input, 2)?;
+        let name_len = take_le_u16(input)? as usize;
+        let name = take(input, name_len)?;
+        let name = String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(name));
+        take(input, 16)?;
+        let product_id = take_le_u16(input)?;
+        let vendor_id = take_le_u16(input)?;
+        take(input, 2)?;
+        out.push_str(&format!(
+            "\n  ID {:04x}:{:04x} {} ({}), class {:#06x}",
+            vendor_id,
+            product_id,
+            name,
+            format_mac(mac),
+            class
+        ));
+    }
+    Some(out)
+}
+
+// same layout as parsed by asahi-wifisync, keys are left out
+fn decode_wlan_networks(value: &[u8]) -> Option<String> {
+    const CHUNK_LEN: usize = 0xc0;
+    if !value.len().is_multiple_of(CHUNK_LEN) {
+        return None;
+    }
+    let mut out = format!("{} networks", value.len() / CHUNK_LEN);
+    for chunk in value.chunks(CHUNK_LEN) {
+        let secure = u32::from_le_bytes(chunk[0x8..0xc].try_into().unwrap()) != 0;
+        let ssid_len = u32::from_le_bytes(chunk[0xc..0x10].try_into().unwrap()) as usize;
+        let ssid = chunk.get(0x10..0x10 + ssid_len)?;
+        out.push_str(&format!(
+            "\n  SSID {}, {}",
+            String::from_utf8_lossy(ssid),
+            if secure { "PSK" } else { "Open" }
+        ));
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let boot = b"EF57347C-0000-AA11-AA11-00306543ECAC:A:B";
+        assert_eq!(
+            decode(b"boot-volume", boot).unwrap(),
+            "partition type EF57347C-0000-AA11-AA11-00306543ECAC, partition A, volume group B"
+        );
+        assert_eq!(decode(b"backlight-level", &[0x10, 0x02]).unwrap(), "528");
+        assert_eq!(decode(b"unknown", b"1"), None);
+
+        let mut bt = vec![1, 2, 3, 4, 5, 6, 1, 4];
+        bt.extend([0xA, 0xB, 0xC, 0xD, 0xE, 0xF, 0x40, 0x25, 0, 0, 4, 0]);
+        bt.extend(b"kbd\0");
+        bt.extend([0; 16]);
+        bt.extend([0x4c, 0x02, 0xac, 0x05, 0, 0]);
+        assert_eq!(
+            decode(b"BluetoothUHEDevices", &bt).unwrap(),
+            "adapter 01:02:03:04:05:06, 1 devices\n  ID 05ac:024c kbd (0A:0B:0C:0D:0E:0F), class 0x2540"
+        );
+        // truncated blobs fall back to the raw value
+        assert_eq!(decode(b"BluetoothUHEDevices", &bt[..20]), None);
+    }
+}
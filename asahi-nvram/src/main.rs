@@ -1,27 +1,65 @@
 // SPDX-License-Identifier: MIT
-use std::{borrow::Cow, fs::OpenOptions, io::Read, process::ExitCode};
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::Read,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use apple_nvram::{nvram_parse, VarType};
+use apple_nvram::{
+    escape_value, format_guid, guid_name, nvram_parse_as, nvram_parse_with_warnings, parse_guid,
+    write_backup, Bank, CountingWriter, MemoryWriter, MinimalEraseWriter, Nvram, NvramVersion,
+    NvramWriter, OwnedVariable, Partition, VarType,
+};
+
+mod decode;
+mod plist;
 
 #[derive(Debug)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 enum Error {
     Parse,
     SectionTooBig,
     ApplyError(std::io::Error),
+    EraseError(std::io::Error),
     MissingPartitionName,
     MissingValue,
     VariableNotFound,
     UnknownPartition,
+    UnsupportedGuid,
+    Unsupported,
+    Blank,
+    NotBlank,
+    DeviceTooSmall,
     InvalidHex,
+    InvalidBank,
+    InvalidWidth,
+    ReadError(std::io::Error),
+    BackupError(std::io::Error),
+    SnapshotError(std::io::Error),
+    NotEnoughSnapshots,
+    UnknownFormat(apple_nvram::Error),
+    ChecksumMismatch(apple_nvram::Error),
+    InvalidPlist(String),
+    // holds the writable node to use instead
+    ReadOnlyDevice(PathBuf),
 }
 
 impl From<apple_nvram::Error> for Error {
     fn from(e: apple_nvram::Error) -> Self {
         match e {
             apple_nvram::Error::ParseError => Error::Parse,
+            apple_nvram::Error::InvalidBank => Error::InvalidBank,
+            e @ apple_nvram::Error::UnknownFormat { .. } => Error::UnknownFormat(e),
+            e @ apple_nvram::Error::ChecksumMismatch { .. } => Error::ChecksumMismatch(e),
             apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
+            apple_nvram::Error::UnsupportedGuid => Error::UnsupportedGuid,
+            apple_nvram::Error::Unsupported => Error::Unsupported,
+            apple_nvram::Error::Blank => Error::Blank,
             apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::EraseError(e) => Error::EraseError(e),
         }
     }
 }
@@ -31,6 +69,25 @@ type Result<T> = std::result::Result<T, Error>;
 fn main() -> ExitCode {
     match real_main() {
         Ok(_) => ExitCode::SUCCESS,
+        Err(Error::UnknownFormat(e) | Error::ChecksumMismatch(e)) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+        Err(Error::EraseError(e)) => {
+            eprintln!("erase failed, nothing was written: {}", e);
+            ExitCode::FAILURE
+        }
+        Err(Error::ReadOnlyDevice(writable)) => {
+            eprintln!(
+                "the device is a read-only node, use {} to modify nvram",
+                writable.display()
+            );
+            ExitCode::FAILURE
+        }
+        Err(Error::Blank) => {
+            eprintln!("device is blank, `asahi-nvram init` creates an empty nvram store");
+            ExitCode::FAILURE
+        }
         Err(e) => {
             eprintln!("{:?}", e);
             ExitCode::FAILURE
@@ -41,85 +98,730 @@ fn main() -> ExitCode {
 fn real_main() -> Result<()> {
     let matches = clap::command!()
         .arg(clap::arg!(-d --device [DEVICE] "Path to the nvram device."))
+        .arg(clap::arg!(-v --verbose "Report how much was erased and written by modifying commands, repeat for more diagnostics").action(clap::ArgAction::Count))
+        .arg(clap::arg!(--"dry-run" "Report what modifying commands would erase and write without changing the device"))
+        .arg(clap::arg!(--"minimal-erase" "Skip erasing a bank when the new contents only clear bits of the old ones, to save erase cycles. Needs NOR flash like the nvram of Apple Silicon machines."))
+        .arg(clap::arg!(-b --bank [BANK] "Use this bank instead of the active one. For recovery, read dumps the raw contents of invalid or empty banks."))
+        .arg(
+            clap::arg!(--format [FORMAT] "Parse as this nvram format instead of detecting it, to see why that format fails to parse")
+                .possible_values(["v3", "v1v2"]),
+        )
+        // rewrites the v3 store header state and flags bytes, given as hex STATE:FLAGS.
+        // their meaning is mostly unknown and firmware may refuse to boot with other values.
+        .arg(
+            clap::Arg::new("unsafe-set-store-flags")
+                .long("unsafe-set-store-flags")
+                .value_name("STATE:FLAGS")
+                .takes_value(true)
+                .hide(true),
+        )
         .subcommand(
             clap::Command::new("read")
                 .about("Read nvram variables")
+                .arg(clap::arg!(--full "Print entire values instead of truncating long ones"))
+                .arg(clap::arg!(--decode "Print a structured interpretation of known variables"))
+                .arg(clap::arg!(--"include-deleted" "Also print old and deleted copies still stored in the nvram"))
+                .arg(clap::arg!(--"all-banks" "Print every stored copy of every variable in all valid banks, oldest first").conflicts_with_all(&["include-deleted", "hexdump", "decode"]))
+                .arg(clap::arg!(--hexdump "Print values as an offset/hex/ASCII dump"))
+                .arg(clap::arg!(--width [WIDTH] "Bytes per line of --hexdump output, 16 by default"))
+                .arg(clap::arg!(--plist "Print the variables as an XML plist like macOS `nvram -x -p`").conflicts_with_all(&["all-banks", "include-deleted", "hexdump", "decode", "full"]))
                 .arg(clap::Arg::new("variable").multiple_values(true)),
         )
+        .subcommand(clap::Command::new("list-partitions").about("List nvram banks and their state"))
+        .subcommand(clap::Command::new("usage").about("Show free space in the active partition"))
         .subcommand(
             clap::Command::new("delete")
-                .about("Delete nvram variables")
+                .about(
+                    "Delete nvram variables, a trailing '*' deletes all variables with that prefix",
+                )
+                .arg(backup_arg())
+                .args(default_partition_args())
                 .arg(clap::Arg::new("variable").multiple_values(true)),
         )
+        .subcommand(
+            clap::Command::new("compare")
+                .about("Compare the variables of two nvram devices or dumps")
+                .arg(clap::Arg::new("device-a").required(true))
+                .arg(clap::Arg::new("device-b").required(true)),
+        )
+        .subcommand(
+            clap::Command::new("snapshot")
+                .about("Save the nvram contents to a timestamped file in a directory, e.g. on shutdown and boot")
+                .arg(clap::Arg::new("dir").required(true)),
+        )
+        .subcommand(
+            clap::Command::new("diff-snapshots")
+                .about("Compare the two most recent snapshots in a directory")
+                .arg(clap::Arg::new("dir").required(true)),
+        )
+        .subcommand(
+            clap::Command::new("init").about("Create an empty v3 nvram store on a blank device"),
+        )
         .subcommand(
             clap::Command::new("write")
                 .about("Write nvram variables")
+                .arg(backup_arg())
+                .args(default_partition_args())
                 .arg(clap::Arg::new("variable=value").multiple_values(true)),
         )
+        .subcommand(
+            clap::Command::new("repair-generation")
+                .about("Renumber the bank generations from 1, oldest first and the active bank last, after they wrapped around or tie")
+                .arg(backup_arg()),
+        )
+        .subcommand(
+            clap::Command::new("import-plist")
+                .about("Write the variables from a macOS `nvram -x -p` plist, keys without a guid are common unless --system is given")
+                .arg(backup_arg())
+                .args(default_partition_args())
+                .arg(clap::Arg::new("file").required(true))
+                .arg(clap::Arg::new("variable").multiple_values(true).help("Only import these variables")),
+        )
         .get_matches();
+    apple_nvram::logger::init(matches.get_count("verbose"));
+    let format = nvram_format(&matches);
+    if let Some(("compare", args)) = matches.subcommand() {
+        let a = args.get_one::<String>("device-a").unwrap();
+        let b = args.get_one::<String>("device-b").unwrap();
+        return print_diff(Path::new(a), Path::new(b), format);
+    }
+    if let Some(("diff-snapshots", args)) = matches.subcommand() {
+        let (a, b) = latest_snapshots(Path::new(args.get_one::<String>("dir").unwrap()))?;
+        println!("Comparing {} and {}", a.display(), b.display());
+        return print_diff(&a, &b, format);
+    }
     let default_name = "/dev/mtd/by-name/nvram".to_owned();
+    let device = matches.get_one::<String>("device").unwrap_or(&default_name);
+    log::debug!("using nvram device {}", device);
+    if let Some(("snapshot", args)) = matches.subcommand() {
+        let dir = Path::new(args.get_one::<String>("dir").unwrap());
+        let path = take_snapshot(Path::new(device), dir)?;
+        println!("Saved nvram contents to {}", path.display());
+        return Ok(());
+    }
+    let dry_run = matches.contains_id("dry-run");
+    let writes = match matches.subcommand_name() {
+        Some("init" | "write" | "delete" | "import-plist" | "repair-generation") => !dry_run,
+        _ => matches.contains_id("unsafe-set-store-flags") && !dry_run,
+    };
+    if let Some(writable) = writes
+        .then(|| apple_nvram::mtd::writable_node(Path::new(device)))
+        .flatten()
+    {
+        return Err(Error::ReadOnlyDevice(writable));
+    }
     let mut file = OpenOptions::new()
         .read(true)
-        .write(true)
-        .open(matches.get_one::<String>("device").unwrap_or(&default_name))
+        .write(writes)
+        .open(device)
         .unwrap();
     let mut data = Vec::new();
     file.read_to_end(&mut data).unwrap();
-    let mut nv = nvram_parse(&data)?;
+    if let Some(("init", _)) = matches.subcommand() {
+        let mut memory = MemoryWriter::new(data.clone());
+        let inner: &mut dyn NvramWriter = if dry_run { &mut memory } else { &mut file };
+        let mut w = CountingWriter::new(inner);
+        init_nvram(&mut w, &data)?;
+        if dry_run {
+            eprintln!(
+                "Dry run: would write {} bytes, the device was not modified",
+                w.written
+            );
+        } else {
+            println!("Created an empty nvram store");
+        }
+        return Ok(());
+    }
+    let mut warnings = Vec::new();
+    let mut nv = parse_nvram(&data, nvram_format(&matches), &mut warnings)?;
+    for w in &warnings {
+        eprintln!("Warning: {}", w);
+        match w {
+            apple_nvram::ParseWarning::NewerBankInvalid { .. } => {
+                eprintln!("Pass --bank to use a specific bank instead");
+            }
+            apple_nvram::ParseWarning::EqualGenerations { .. } => {
+                eprintln!("Pass --bank to pick the right one, then run repair-generation");
+            }
+            _ => {}
+        }
+    }
+    if let Some(info) = apple_nvram::mtd::nvram_info(file.as_raw_fd()) {
+        log::debug!(
+            "mtd size {:#x}, erase block size {:#x}",
+            info.size,
+            info.erasesize
+        );
+        for w in apple_nvram::mtd::check_layout(&info, data.len(), nv.bank_size()) {
+            eprintln!("Warning: {}", w);
+        }
+    }
+    if let Some(bank) = matches.get_one::<String>("bank") {
+        let index = bank.parse::<usize>().map_err(|_| Error::InvalidBank)?;
+        if let Err(e) = nv.set_active(index) {
+            // invalid and empty banks can't be parsed, but reading their raw bytes can still help
+            let size = nv.bank_size();
+            let start = index.checked_mul(size).ok_or(Error::InvalidBank)?;
+            let end = start.checked_add(size).ok_or(Error::InvalidBank)?;
+            match (matches.subcommand_name(), data.get(start..end)) {
+                (Some("read"), Some(raw)) => {
+                    hex_dump(raw, 16);
+                    return Ok(());
+                }
+                _ => return Err(e.into()),
+            }
+        }
+    }
+    let backup = match matches.subcommand() {
+        _ if dry_run => None,
+        Some((_, args)) if args.try_contains_id("backup").unwrap_or(false) => {
+            let path = args.get_one::<String>("backup").map(Path::new);
+            let path = write_backup(&data, path).map_err(Error::BackupError)?;
+            eprintln!("Saved current nvram contents to {}", path.display());
+            Some(path)
+        }
+        _ => None,
+    };
+    let mut memory = MemoryWriter::new(data.clone());
+    let inner: &mut dyn NvramWriter = if dry_run { &mut memory } else { &mut file };
+    let mut w = CountingWriter::new(inner);
+    let result = if matches.contains_id("minimal-erase") {
+        // inside the counting writer, so skipped erases aren't counted
+        let mut minimal = MinimalEraseWriter::new(&mut w);
+        let result = run_command(&matches, nv.as_mut(), &mut minimal);
+        log::info!("{} erases skipped", minimal.skipped_erases);
+        result
+    } else {
+        run_command(&matches, nv.as_mut(), &mut w)
+    };
+    if dry_run {
+        eprintln!(
+            "Dry run: would erase {} bytes and write {} bytes, the device was not modified",
+            w.erased, w.written
+        );
+    } else if w.erased != 0 || w.written != 0 {
+        log::info!(
+            "erased {} bytes, wrote {} bytes, active bank {}",
+            w.erased,
+            w.written,
+            nv.active_bank()
+        );
+    }
+    if let (Err(_), Some(path)) = (&result, backup) {
+        eprintln!(
+            "The nvram contents before this change were saved to {}",
+            path.display()
+        );
+    }
+    result
+}
+
+fn backup_arg() -> clap::Arg<'static> {
+    clap::Arg::new("backup")
+        .long("backup")
+        .value_name("PATH")
+        .min_values(0)
+        .require_equals(true)
+        .help("Save the current nvram contents before modifying them, to a temporary file if no path is given")
+}
+
+fn run_command<'a>(
+    matches: &clap::ArgMatches,
+    nv: &mut dyn Nvram<'a>,
+    w: &mut dyn NvramWriter,
+) -> Result<()> {
+    match matches.get_one::<String>("unsafe-set-store-flags") {
+        Some(arg) => set_store_flags(nv, w, arg),
+        None => run_subcommand(matches, nv, w),
+    }
+}
+
+fn run_subcommand<'a>(
+    matches: &clap::ArgMatches,
+    nv: &mut dyn Nvram<'a>,
+    w: &mut dyn NvramWriter,
+) -> Result<()> {
     match matches.subcommand() {
+        Some(("read", args)) if args.contains_id("all-banks") => {
+            print_all_banks(nv, args.contains_id("full"));
+        }
+        Some(("read", args)) if args.contains_id("plist") => {
+            let active = nv.active_part_mut();
+            let mut vars = match args.get_many::<String>("variable") {
+                Some(vars) => vars
+                    .map(|var| get_variable(active, var))
+                    .collect::<Result<Vec<_>>>()?,
+                None => active.variables().collect(),
+            }
+            .into_iter()
+            .map(|v| (plist_key(v), v.value().into_owned()))
+            .collect::<Vec<_>>();
+            print!("{}", plist::write(&mut vars));
+        }
         Some(("read", args)) => {
+            let bank = nv.active_bank();
+            let bank_size = nv.bank_size();
             let active = nv.active_part_mut();
+            let full = args.contains_id("full");
+            let decode = args.contains_id("decode");
+            let hexdump_width = match args.get_one::<String>("width") {
+                Some(width) => match width.parse::<usize>() {
+                    Ok(width) if width > 0 => width,
+                    _ => return Err(Error::InvalidWidth),
+                },
+                None => 16,
+            };
+            let hexdump = args.contains_id("hexdump");
+            let print = |v: &dyn apple_nvram::Variable| {
+                let key = String::from_utf8_lossy(v.key());
+                if hexdump {
+                    println!("{}:{}:", guid_name(&v.guid()), key);
+                    hex_dump(&v.value(), hexdump_width);
+                } else if let Some(decoded) = decode
+                    .then(|| decode::decode(v.key(), &v.value()))
+                    .flatten()
+                {
+                    println!("{}:{}: {}", guid_name(&v.guid()), key, decoded);
+                } else if full {
+                    println!(
+                        "{}:{}={}",
+                        guid_name(&v.guid()),
+                        key,
+                        escape_value(v.value_bytes(), None)
+                    );
+                } else {
+                    println!("{}", v);
+                }
+            };
 
             let vars = args.get_many::<String>("variable");
-            if let Some(vars) = vars {
+            if let Some(vars) = vars.clone() {
                 for var in vars {
-                    let (part, name) = var.split_once(':').ok_or(Error::MissingPartitionName)?;
-                    let typ = part_by_name(part)?;
-                    let v = active
-                        .get_variable(name.as_bytes(), typ)
-                        .ok_or(Error::VariableNotFound)?;
-                    println!("{}", v);
+                    print(get_variable(active, var)?);
                 }
             } else {
                 for var in active.variables() {
-                    println!("{}", var);
+                    print(var);
+                }
+                // on stderr to keep piped output to just the variables
+                eprintln!(
+                    "{} variables, {} bytes used, bank {} active, generation {}",
+                    active.variables().count(),
+                    bank_size - active.free_bytes_total(),
+                    bank,
+                    active.generation()
+                );
+            }
+
+            if args.contains_id("include-deleted") {
+                let active = nv.active_part_mut();
+                println!("stale entries in bank {} ({}), oldest first:", bank, active);
+                let wanted = vars
+                    .map(|vars| {
+                        vars.map(|var| split_var_name(var, None))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .transpose()?;
+                let limit = if full {
+                    None
+                } else {
+                    Some(apple_nvram::DISPLAY_VALUE_LIMIT)
+                };
+                for v in active.stale_variables() {
+                    if let Some(wanted) = &wanted {
+                        if !wanted
+                            .iter()
+                            .any(|&(guid, name)| guid == v.guid && name.as_bytes() == v.key)
+                        {
+                            continue;
+                        }
+                    }
+                    println!("state 0x{:02x} {}", v.state.unwrap_or(0), v.format(limit));
                 }
             }
         }
         Some(("write", args)) => {
             let vars = args.get_many::<String>("variable=value");
-            nv.prepare_for_write();
-            let active = nv.active_part_mut();
-            for var in vars.unwrap_or_default() {
-                let (key, value) = var.split_once('=').ok_or(Error::MissingValue)?;
-                let (part, name) = key.split_once(':').ok_or(Error::MissingPartitionName)?;
-                let typ = part_by_name(part)?;
-                active.insert_variable(name.as_bytes(), Cow::Owned(read_var(value)?), typ);
-            }
-            nv.apply(&mut file)?;
+            write_variables(nv, w, vars.unwrap_or_default(), default_partition(args))?;
+        }
+        Some(("import-plist", args)) => {
+            let text = std::fs::read_to_string(args.get_one::<String>("file").unwrap())
+                .map_err(Error::ReadError)?;
+            let default = default_partition(args).unwrap_or(VarType::Common.guid());
+            import_plist(nv, w, &text, default, args.get_many::<String>("variable"))?;
         }
         Some(("delete", args)) => {
             let vars = args.get_many::<String>("variable");
+            let default = default_partition(args);
             nv.prepare_for_write();
             let active = nv.active_part_mut();
             for var in vars.unwrap_or_default() {
-                let (part, name) = var.split_once(':').ok_or(Error::MissingPartitionName)?;
-                let typ = part_by_name(part)?;
-                active.remove_variable(name.as_bytes(), typ);
+                let (guid, name) = split_var_name(var, default)?;
+                if let Some(prefix) = name.strip_suffix('*') {
+                    let keys: Vec<Vec<u8>> = active
+                        .variables()
+                        .filter(|v| v.guid() == guid && v.key().starts_with(prefix.as_bytes()))
+                        .map(|v| v.key().to_vec())
+                        .collect();
+                    for key in keys {
+                        active.remove_variable_by_guid(&key, &guid);
+                        println!("{}:{}", guid_name(&guid), String::from_utf8_lossy(&key));
+                    }
+                } else {
+                    active.remove_variable_by_guid(name.as_bytes(), &guid);
+                }
+            }
+            nv.apply(w)?;
+        }
+        Some(("usage", _)) => {
+            let active = nv.active_part_mut();
+            for typ in [VarType::Common, VarType::System] {
+                println!("{}: {} bytes free", typ, active.free_bytes(typ));
+            }
+            println!("total: {} bytes free", active.free_bytes_total());
+        }
+        Some(("repair-generation", _)) => {
+            nv.repair_generations(w)?;
+            for (i, bank) in nv.banks().enumerate() {
+                if let Bank::Valid(p) = bank {
+                    let marker = if i == nv.active_bank() { "*" } else { " " };
+                    println!("{}{}: generation {}", marker, i, p.generation());
+                }
+            }
+        }
+        Some(("list-partitions", _)) => {
+            for (i, bank) in nv.banks().enumerate() {
+                let marker = if i == nv.active_bank() { "*" } else { " " };
+                match bank {
+                    Bank::Valid(p) => println!("{}{}: valid, {}", marker, i, p),
+                    Bank::Invalid => println!("{}{}: invalid", marker, i),
+                    Bank::Empty => println!("{}{}: empty", marker, i),
+                }
             }
-            nv.apply(&mut file)?;
         }
         _ => {}
     }
     Ok(())
 }
 
-fn part_by_name(name: &str) -> Result<VarType> {
-    match name {
-        "common" => Ok(VarType::Common),
-        "system" => Ok(VarType::System),
-        _ => Err(Error::UnknownPartition),
+// macOS shows variables with the common guid by name alone and prefixes all others with their guid
+fn plist_key(v: &dyn apple_nvram::Variable) -> String {
+    let key = String::from_utf8_lossy(v.key());
+    match VarType::from_guid(&v.guid()) {
+        Some(VarType::Common) => key.into_owned(),
+        _ => format!("{}:{}", format_guid(&v.guid()), key),
+    }
+}
+
+// there are no timestamps, banks are ordered by generation and entries by their position
+fn print_all_banks(nv: &dyn Nvram, full: bool) {
+    let limit = if full {
+        None
+    } else {
+        Some(apple_nvram::DISPLAY_VALUE_LIMIT)
+    };
+    let mut banks: Vec<(usize, &dyn Partition)> = nv
+        .banks()
+        .enumerate()
+        .filter_map(|(i, bank)| match bank {
+            Bank::Valid(p) => Some((i, p)),
+            _ => None,
+        })
+        .collect();
+    banks.sort_by_key(|(_, p)| p.generation());
+    for (i, p) in banks {
+        println!("bank {}, generation 0x{:x}:", i, p.generation());
+        for (seq, v) in p.history().into_iter().enumerate() {
+            let state = v.state.map(|s| format!(" state 0x{:02x}", s));
+            println!(
+                "  {:4}{} {}",
+                seq,
+                state.unwrap_or_default(),
+                v.format(limit)
+            );
+        }
+    }
+}
+
+fn hex_dump(data: &[u8], width: usize) {
+    for line in hex_dump_lines(data, width) {
+        println!("{}", line);
+    }
+}
+
+fn hex_dump_lines(data: &[u8], width: usize) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut prev: Option<&[u8]> = None;
+    let mut skipping = false;
+    for (i, line) in data.chunks(width).enumerate() {
+        // like hexdump, repeated lines are shown as a single '*'
+        if prev == Some(line) {
+            if !skipping {
+                out.push("*".to_owned());
+                skipping = true;
+            }
+            continue;
+        }
+        skipping = false;
+        prev = Some(line);
+        let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push(format!(
+            "{:08x}  {:<hex_width$}  |{}|",
+            i * width,
+            hex.join(" "),
+            ascii,
+            hex_width = width * 3 - 1
+        ));
+    }
+    out.push(format!("{:08x}", data.len()));
+    out
+}
+
+fn nvram_format(matches: &clap::ArgMatches) -> Option<NvramVersion> {
+    match matches.get_one::<String>("format")?.as_str() {
+        "v1v2" => Some(NvramVersion::V1V2),
+        _ => Some(NvramVersion::V3),
+    }
+}
+
+// a forced format skips detection, so there is nothing to warn about
+fn parse_nvram<'a>(
+    data: &'a [u8],
+    format: Option<NvramVersion>,
+    warnings: &mut Vec<apple_nvram::ParseWarning>,
+) -> Result<Box<dyn Nvram<'a> + 'a>> {
+    Ok(match format {
+        Some(version) => {
+            let nv = nvram_parse_as(data, version)?;
+            warnings.extend(apple_nvram::bank_warnings(nv.as_ref()));
+            nv
+        }
+        None => nvram_parse_with_warnings(data, warnings)?,
+    })
+}
+
+fn read_snapshot(path: &Path, format: Option<NvramVersion>) -> Result<Vec<OwnedVariable>> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(Error::ReadError)?;
+    let mut warnings = Vec::new();
+    let nv = parse_nvram(&data, format, &mut warnings)?;
+    for w in &warnings {
+        eprintln!("Warning: {}: {}", path.display(), w);
+    }
+    let snapshot = nv.to_owned_snapshot();
+    Ok(snapshot)
+}
+
+fn print_diff(a: &Path, b: &Path, format: Option<NvramVersion>) -> Result<()> {
+    let diff = diff_snapshots(&read_snapshot(a, format)?, &read_snapshot(b, format)?);
+    if diff.is_empty() {
+        println!("No differences");
+    }
+    for line in diff {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+const SNAPSHOT_PREFIX: &str = "nvram-";
+
+// copies the raw device contents to dir/nvram-<unix time>.bin
+fn take_snapshot(device: &Path, dir: &Path) -> Result<PathBuf> {
+    let data = std::fs::read(device).map_err(Error::ReadError)?;
+    std::fs::create_dir_all(dir).map_err(Error::SnapshotError)?;
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = dir.join(format!("{}{}.bin", SNAPSHOT_PREFIX, time.as_secs()));
+    write_backup(&data, Some(&path)).map_err(Error::SnapshotError)
+}
+
+// the second newest and the newest snapshot in dir, by the time in their name
+fn latest_snapshots(dir: &Path) -> Result<(PathBuf, PathBuf)> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(Error::SnapshotError)? {
+        let path = entry.map_err(Error::SnapshotError)?.path();
+        let time = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(SNAPSHOT_PREFIX))
+            .and_then(|n| n.strip_suffix(".bin"))
+            .and_then(|t| t.parse::<u64>().ok());
+        if let Some(time) = time {
+            snapshots.push((time, path));
+        }
+    }
+    snapshots.sort();
+    match snapshots.len() {
+        0 | 1 => Err(Error::NotEnoughSnapshots),
+        n => Ok((snapshots[n - 2].1.clone(), snapshots[n - 1].1.clone())),
+    }
+}
+
+// lists variables that differ between two snapshots, "<" for a and ">" for b
+fn diff_snapshots(a: &[OwnedVariable], b: &[OwnedVariable]) -> Vec<String> {
+    let line = |prefix, v: &OwnedVariable| format!("{} {}", prefix, v.format(None));
+    let find = |vars: &[OwnedVariable], v: &OwnedVariable| {
+        vars.iter()
+            .find(|o| o.guid == v.guid && o.key == v.key)
+            .map(|o| o.value == v.value)
+    };
+    let mut out = Vec::new();
+    for v in a {
+        if find(b, v) != Some(true) {
+            out.push(line("<", v));
+        }
+    }
+    for v in b {
+        if find(a, v) != Some(true) {
+            out.push(line(">", v));
+        }
+    }
+    out
+}
+
+// only done on erased devices, anything else could still hold recoverable variables
+fn init_nvram(w: &mut dyn NvramWriter, data: &[u8]) -> Result<()> {
+    // the limits apple firmware uses for a single 64k bank
+    const SYSTEM_SIZE: u32 = 0x4000;
+    const COMMON_SIZE: u32 = 0xC000;
+    if !data.iter().all(|&b| b == 0xFF) {
+        return Err(Error::NotBlank);
+    }
+    let image = apple_nvram::v3::Nvram::empty(1, SYSTEM_SIZE, COMMON_SIZE);
+    if data.len() < image.len() {
+        return Err(Error::DeviceTooSmall);
+    }
+    w.write_all(0, &image).map_err(Error::ApplyError)?;
+    Ok(())
+}
+
+fn set_store_flags(nv: &mut dyn Nvram, w: &mut dyn NvramWriter, arg: &str) -> Result<()> {
+    let (state, flags) = arg.split_once(':').ok_or(Error::InvalidHex)?;
+    let parse =
+        |s: &str| u8::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| Error::InvalidHex);
+    let (state, flags) = (parse(state)?, parse(flags)?);
+    let (old_state, old_flags) = nv.store_header_flags().ok_or(Error::Unsupported)?;
+    eprintln!(
+        "Warning: changing store header state 0x{:02x} -> 0x{:02x}, flags 0x{:02x} -> 0x{:02x}. \
+         This is untested and may leave the machine unbootable.",
+        old_state, state, old_flags, flags
+    );
+    nv.set_store_header_flags(w, state, flags)?;
+    Ok(())
+}
+
+// writes variables given as partition:name=value
+fn write_variables<'a, 'b>(
+    nv: &mut dyn Nvram<'a>,
+    w: &mut dyn NvramWriter,
+    vars: impl Iterator<Item = &'b String>,
+    default: Option<[u8; 16]>,
+) -> Result<()> {
+    nv.prepare_for_write();
+    let active = nv.active_part_mut();
+    for var in vars {
+        let (key, value) = var.split_once('=').ok_or(Error::MissingValue)?;
+        let (guid, name) = split_var_name(key, default)?;
+        active.insert_variable_by_guid(name.as_bytes(), Cow::Owned(read_var(value)?), &guid)?;
+    }
+    // apply handles moving v3 nvram to the next bank when the current one is full
+    nv.apply(w)?;
+    Ok(())
+}
+
+// every variable is checked before any is inserted, and apply refuses to write if they don't fit,
+// so a bad file leaves the nvram untouched
+fn import_plist<'a, 'b>(
+    nv: &mut dyn Nvram<'a>,
+    w: &mut dyn NvramWriter,
+    text: &str,
+    default: [u8; 16],
+    wanted: Option<impl Iterator<Item = &'b String>>,
+) -> Result<()> {
+    let vars = plist::parse(text).map_err(Error::InvalidPlist)?;
+    let vars = vars
+        .iter()
+        .map(|(key, value)| Ok((split_var_name(key, Some(default))?, value)))
+        .collect::<Result<Vec<_>>>()?;
+    let vars = match wanted {
+        Some(wanted) => wanted
+            .map(|var| {
+                let wanted = split_var_name(var, Some(default))?;
+                vars.iter()
+                    .find(|(id, _)| *id == wanted)
+                    .cloned()
+                    .ok_or(Error::VariableNotFound)
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => vars,
+    };
+    nv.prepare_for_write();
+    let active = nv.active_part_mut();
+    for ((guid, name), value) in &vars {
+        active.insert_variable_by_guid(name.as_bytes(), Cow::Owned(value.to_vec()), guid)?;
+        println!("{}:{}", guid_name(guid), name);
     }
+    nv.apply(w)?;
+    Ok(())
+}
+
+fn default_partition_args() -> [clap::Arg<'static>; 2] {
+    [
+        clap::arg!(--system "Use the system partition for variables without a partition prefix")
+            .conflicts_with("common"),
+        clap::arg!(--common "Use the common partition for variables without a partition prefix"),
+    ]
+}
+
+fn default_partition(args: &clap::ArgMatches) -> Option<[u8; 16]> {
+    if args.contains_id("system") {
+        Some(VarType::System.guid())
+    } else if args.contains_id("common") {
+        Some(VarType::Common.guid())
+    } else {
+        None
+    }
+}
+
+// the partition can be common, system or the guid of any other vendor. with a default, a
+// name without a known partition prefix is used as is.
+fn split_var_name(var: &str, default: Option<[u8; 16]>) -> Result<([u8; 16], &str)> {
+    let Some((part, name)) = var.split_once(':') else {
+        return Ok((default.ok_or(Error::MissingPartitionName)?, var));
+    };
+    let guid = match part {
+        "common" => VarType::Common.guid(),
+        "system" => VarType::System.guid(),
+        _ => match (parse_guid(part), default) {
+            (Some(guid), _) => guid,
+            (None, Some(default)) => return Ok((default, var)),
+            (None, None) => return Err(Error::UnknownPartition),
+        },
+    };
+    Ok((guid, name))
+}
+
+// looks up a variable given as partition:name
+fn get_variable<'a, 'b>(
+    part: &'b dyn Partition<'a>,
+    var: &str,
+) -> Result<&'b dyn apple_nvram::Variable<'a>> {
+    let (guid, name) = split_var_name(var, None)?;
+    part.get_variable_by_guid(name.as_bytes(), &guid)
+        .ok_or(Error::VariableNotFound)
 }
 
 fn read_var(val: &str) -> Result<Vec<u8>> {
@@ -143,3 +845,270 @@ fn read_var(val: &str) -> Result<Vec<u8>> {
     }
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apple_nvram::v1v2;
+    use std::collections::HashMap;
+
+    fn empty_partition() -> v1v2::Partition<'static> {
+        let section = |name| v1v2::Section {
+            header: v1v2::CHRPHeader {
+                name,
+                size: 0x100,
+                signature: 0x70,
+            },
+            values: HashMap::new(),
+        };
+        v1v2::Partition {
+            header: v1v2::CHRPHeader {
+                name: b"nvram",
+                size: 0x200,
+                signature: 0x5A,
+            },
+            generation: 1,
+            common: section(b"common"),
+            system: section(b"system"),
+        }
+    }
+
+    #[test]
+    fn test_read_honors_partition_prefix() -> Result<()> {
+        let mut part = empty_partition();
+        part.insert_variable(b"foo", Cow::Borrowed(b"common-value"), VarType::Common);
+        part.insert_variable(b"foo", Cow::Borrowed(b"system-value"), VarType::System);
+        part.insert_variable(b"bar", Cow::Borrowed(b"only-system"), VarType::System);
+
+        let common = get_variable(&part, "common:foo")?;
+        assert_eq!(common.typ(), VarType::Common);
+        assert_eq!(common.value(), Cow::Borrowed(b"common-value"));
+        let system = get_variable(&part, "system:foo")?;
+        assert_eq!(system.typ(), VarType::System);
+        assert_eq!(system.value(), Cow::Borrowed(b"system-value"));
+
+        assert!(matches!(
+            get_variable(&part, "common:bar"),
+            Err(Error::VariableNotFound)
+        ));
+        assert!(matches!(
+            get_variable(&part, "foo"),
+            Err(Error::MissingPartitionName)
+        ));
+        assert!(matches!(
+            get_variable(&part, "other:foo"),
+            Err(Error::UnknownPartition)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_var_name() -> Result<()> {
+        assert_eq!(
+            split_var_name("system:boot-volume", None)?,
+            (VarType::System.guid(), "boot-volume")
+        );
+        let (guid, name) = split_var_name("8be4df61-93ca-11d2-aa0d-00e098032b8c:Boot0000", None)?;
+        assert_eq!(guid_name(&guid), "8BE4DF61-93CA-11D2-AA0D-00E098032B8C");
+        assert_eq!(name, "Boot0000");
+        assert_eq!(
+            guid_name(&split_var_name("7C436110-AB2A-4BBB-A880-FE41995C9F82:a", None)?.0),
+            "common"
+        );
+        assert!(matches!(
+            split_var_name("8be4df61-93ca:foo", None),
+            Err(Error::UnknownPartition)
+        ));
+
+        // --system and --common only apply to names without a known prefix
+        let system = Some(VarType::System.guid());
+        assert_eq!(
+            split_var_name("boot-volume", system)?,
+            (VarType::System.guid(), "boot-volume")
+        );
+        assert_eq!(
+            split_var_name("common:boot-volume", system)?,
+            (VarType::Common.guid(), "boot-volume")
+        );
+        assert_eq!(
+            split_var_name("weird:name", system)?,
+            (VarType::System.guid(), "weird:name")
+        );
+
+        // v1v2 only stores the apple guids
+        let mut part = empty_partition();
+        assert!(matches!(
+            part.insert_variable_by_guid(b"foo", Cow::Borrowed(b"bar"), &guid),
+            Err(apple_nvram::Error::UnsupportedGuid)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(
+            hex_dump_lines(b"abc\x00\x01defgh", 4),
+            [
+                "00000000  61 62 63 00  |abc.|",
+                "00000004  01 64 65 66  |.def|",
+                "00000008  67 68        |gh|",
+                "0000000a",
+            ]
+        );
+        assert_eq!(
+            hex_dump_lines(&[0; 40], 8),
+            [
+                "00000000  00 00 00 00 00 00 00 00  |........|",
+                "*",
+                "00000028",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_switches_bank() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("asahi-nvram-test-{}", std::process::id()));
+        let image = apple_nvram::v3::Nvram::empty(2, 0x4000, 0xC000);
+        std::fs::write(&path, image).unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        // every write appends another 4k copy until the first bank is full
+        let mut switched = false;
+        for i in 0..20 {
+            let data = std::fs::read(&path).unwrap();
+            let mut nv = apple_nvram::nvram_parse(&data)?;
+            let var = format!(
+                "system:fill={}",
+                char::from(b'a' + i).to_string().repeat(4000)
+            );
+            write_variables(nv.as_mut(), &mut file, [var].iter(), None)?;
+
+            let data = std::fs::read(&path).unwrap();
+            let nv = apple_nvram::nvram_parse(&data)?;
+            switched |= nv.active_bank() == 1;
+            let value = nv.get(VarType::System, b"fill").unwrap();
+            assert_eq!(value, vec![b'a' + i; 4000]);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(switched);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_plist() -> Result<()> {
+        let image = apple_nvram::v3::Nvram::empty(2, 0x4000, 0xC000);
+        let mut w = MemoryWriter::new(image.clone());
+        let mut nv = apple_nvram::nvram_parse(&image)?;
+        let text = plist::write(&mut [
+            ("SystemAudioVolume".to_owned(), vec![0x47]),
+            (
+                "40A0DDD2-77F8-4392-B4A3-1E7304206516:boot-volume".to_owned(),
+                b"EF57347C-0000-AA11-AA11-00306543ECAC:A:B".to_vec(),
+            ),
+        ]);
+        let wanted = ["system:boot-volume".to_owned(), "missing".to_owned()];
+        assert!(matches!(
+            import_plist(
+                nv.as_mut(),
+                &mut w,
+                &text,
+                VarType::Common.guid(),
+                Some(wanted.iter())
+            ),
+            Err(Error::VariableNotFound)
+        ));
+        import_plist(
+            nv.as_mut(),
+            &mut w,
+            &text,
+            VarType::Common.guid(),
+            None::<std::slice::Iter<String>>,
+        )?;
+
+        let nv = apple_nvram::nvram_parse(w.get_data())?;
+        assert_eq!(
+            nv.get(VarType::Common, b"SystemAudioVolume").unwrap(),
+            [0x47]
+        );
+        assert_eq!(
+            nv.get(VarType::System, b"boot-volume").unwrap(),
+            b"EF57347C-0000-AA11-AA11-00306543ECAC:A:B"
+        );
+        assert!(nv.get(VarType::System, b"SystemAudioVolume").is_none());
+
+        assert!(matches!(
+            import_plist(
+                apple_nvram::nvram_parse(&image)?.as_mut(),
+                &mut MemoryWriter::new(image.clone()),
+                "<dict><key>a</key>",
+                VarType::Common.guid(),
+                None::<std::slice::Iter<String>>,
+            ),
+            Err(Error::InvalidPlist(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_snapshots() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("asahi-nvram-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(matches!(
+            latest_snapshots(&dir),
+            Err(Error::NotEnoughSnapshots)
+        ));
+        for name in [
+            "nvram-5.bin",
+            "nvram-100.bin",
+            "nvram-20.bin",
+            "nvram-x.bin",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let (a, b) = latest_snapshots(&dir)?;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            (a, b),
+            (dir.join("nvram-20.bin"), dir.join("nvram-100.bin"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let var = |typ, key: &[u8], value: &[u8]| OwnedVariable {
+            partition: typ,
+            key: key.to_vec(),
+            value: value.to_vec(),
+            guid: VarType::guid(&typ),
+            attrs: None,
+            state: None,
+        };
+        let a = [
+            var(VarType::System, b"same", b"1"),
+            var(VarType::System, b"changed", b"old"),
+            var(VarType::Common, b"only-a", b"\0"),
+        ];
+        let b = [
+            var(VarType::System, b"changed", b"new"),
+            var(VarType::System, b"same", b"1"),
+            var(VarType::System, b"only-a", b"typ"),
+        ];
+        assert_eq!(
+            diff_snapshots(&a, &b),
+            [
+                "< system:changed=old",
+                "< common:only-a=%00",
+                "> system:changed=new",
+                "> system:only-a=typ",
+            ]
+        );
+        assert!(diff_snapshots(&a, &a).is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: MIT
+// the xml plist layout `nvram -x -p` prints on macOS, so dumps from both systems can be diffed
+// and replayed. only what that output uses is supported: a single dict of data or string values.
+
+use apple_nvram::{base64_decode, base64_encode};
+
+// CoreFoundation wraps data at this many characters
+const LINE_LEN: usize = 76;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some((text, entity)) = rest.split_once('&') {
+        out.push_str(text);
+        let (name, r) = entity
+            .split_once(';')
+            .ok_or_else(|| format!("unterminated entity in {}", s))?;
+        out.push(match name {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => return Err(format!("unknown entity &{};", name)),
+        });
+        rest = r;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// takes <tag>content</tag> or <tag/> off the front of rest
+fn element<'a>(rest: &mut &'a str, tag: &str) -> Result<Option<&'a str>, String> {
+    if let Some(r) = rest.strip_prefix(&format!("<{}/>", tag)) {
+        *rest = r;
+        return Ok(Some(""));
+    }
+    let Some(r) = rest.strip_prefix(&format!("<{}>", tag)) else {
+        return Ok(None);
+    };
+    let (content, r) = r
+        .split_once(&format!("</{}>", tag))
+        .ok_or_else(|| format!("unterminated <{}>", tag))?;
+    *rest = r.trim_start();
+    Ok(Some(content))
+}
+
+// returns the keys as they are in the file, in file order
+pub fn parse(text: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let (_, mut rest) = text
+        .split_once("<dict>")
+        .ok_or_else(|| "no <dict> found".to_owned())?;
+    let mut vars = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with("</dict>") {
+            return Ok(vars);
+        }
+        let key = element(&mut rest, "key")?.ok_or_else(|| "expected <key>".to_owned())?;
+        let key = unescape(key)?;
+        let value = if let Some(data) = element(&mut rest, "data")? {
+            base64_decode(data).ok_or_else(|| format!("invalid data for {}", key))?
+        } else if let Some(s) = element(&mut rest, "string")? {
+            unescape(s)?.into_bytes()
+        } else {
+            return Err(format!("unsupported value for {}", key));
+        };
+        vars.push((key, value));
+    }
+}
+
+// keys are sorted like CoreFoundation does, the caller picks how they are named
+pub fn write(vars: &mut [(String, Vec<u8>)]) -> String {
+    vars.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+        "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n<dict>\n"
+    ));
+    for (key, value) in vars.iter() {
+        out.push_str(&format!("\t<key>{}</key>\n\t<data>\n", escape(key)));
+        let encoded = base64_encode(value);
+        for line in encoded.as_bytes().chunks(LINE_LEN) {
+            out.push('\t');
+            out.push_str(std::str::from_utf8(line).unwrap());
+            out.push('\n');
+        }
+        out.push_str("\t</data>\n");
+    }
+    out.push_str("</dict>\n</plist>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut vars = vec![
+            ("b&c".to_owned(), vec![0x47]),
+            ("a".to_owned(), (0..100).collect()),
+            ("empty".to_owned(), Vec::new()),
+        ];
+        let parsed = parse(&write(&mut vars)).unwrap();
+        assert_eq!(parsed, vars);
+
+        let text = "<plist version=\"1.0\">\n<dict>\n\t<key>s</key>\n\t<string>a &lt; b</string>\n\
+                    \t<key>e</key><data/>\n</dict>\n</plist>\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                ("s".to_owned(), b"a < b".to_vec()),
+                ("e".to_owned(), Vec::new())
+            ]
+        );
+
+        assert!(parse("<plist></plist>").is_err());
+        assert!(parse("<dict><key>a</key><integer>1</integer></dict>").is_err());
+        assert!(parse("<dict><key>a</key><data>Zg</data></dict>").is_err());
+        assert!(parse("<dict><key>a</key><data>Zg==").is_err());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut vars = vec![
+            ("b&c".to_owned(), vec![0x47]),
+            ("a".to_owned(), vec![0; 60]),
+        ];
+        let out = write(&mut vars);
+        let body = out.split_once("<dict>\n").unwrap().1;
+        assert_eq!(
+            body,
+            format!(
+                "\t<key>a</key>\n\t<data>\n\t{}\n\t{}\n\t</data>\n\
+                 \t<key>b&amp;c</key>\n\t<data>\n\tRw==\n\t</data>\n</dict>\n</plist>\n",
+                "A".repeat(76),
+                "A".repeat(4)
+            )
+        );
+    }
+}
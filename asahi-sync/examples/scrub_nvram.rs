@@ -0,0 +1,42 @@
+/* SPDX-License-Identifier: MIT */
+
+// zeroes the Bluetooth pairing keys and Wi-Fi keys in a v3 nvram dump, so it can be shared in a
+// bug report or added as a test fixture:
+//   cargo run -p asahi-sync --no-default-features --example scrub_nvram -- nvram.bin scrubbed.bin
+// only the active bank's current variables are kept, replaced copies of the keys would still be
+// in the stale entries and the other banks
+
+use std::{borrow::Cow, env, fs, process::ExitCode};
+
+use apple_nvram::{v3, Nvram, VarType};
+
+use asahi_btsync::{scrub_bt_data, BT_VAR};
+use asahi_wifisync::{scrub_wlan_data, WLAN_VAR};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let [_, input, output] = &args[..] else {
+        eprintln!("usage: scrub_nvram <dump> <output>");
+        return ExitCode::FAILURE;
+    };
+    let data = fs::read(input).expect("Failed to read the dump");
+    let mut nv = v3::Nvram::parse(&data).expect("Not a v3 nvram dump");
+    let part = nv.active_part_mut();
+
+    if let Some(var) = part.get_variable(BT_VAR.as_bytes(), VarType::System) {
+        let value = scrub_bt_data(&var.value()).expect("Failed to parse Bluetooth devices");
+        part.insert_variable(BT_VAR.as_bytes(), Cow::Owned(value), VarType::System);
+    }
+    if let Some(var) = part.get_variable(WLAN_VAR.as_bytes(), VarType::System) {
+        let value = scrub_wlan_data(&var.value()).expect("Failed to parse Wi-Fi networks");
+        part.insert_variable(WLAN_VAR.as_bytes(), Cow::Owned(value), VarType::System);
+    }
+
+    fs::write(output, nv.compacted()).expect("Failed to write the scrubbed dump");
+    eprintln!(
+        "Keys zeroed. Device names and addresses, SSIDs and every other variable are left as \
+         they were, check them before sharing {}",
+        output
+    );
+    ExitCode::SUCCESS
+}
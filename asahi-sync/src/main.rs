@@ -0,0 +1,107 @@
+/* SPDX-License-Identifier: MIT */
+
+use std::{fs::OpenOptions, io, time::Duration};
+
+use apple_nvram::{nvram_parse, VarType};
+
+use asahi_btsync::BT_VAR;
+use asahi_wifisync::WLAN_VAR;
+
+#[derive(Debug)]
+#[allow(dead_code)]
+enum Error {
+    Nvram(apple_nvram::Error),
+    FileIO(io::Error),
+    Bluetooth(asahi_btsync::Error),
+    Wlan(asahi_wifisync::Error),
+}
+
+impl From<apple_nvram::Error> for Error {
+    fn from(e: apple_nvram::Error) -> Self {
+        Error::Nvram(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::FileIO(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn main() {
+    real_main().unwrap();
+}
+
+// reads the nvram once and hands the same parse to both importers, so they can't see two
+// different versions of it
+fn real_main() -> Result<()> {
+    let matches = clap::command!()
+        .arg(clap::arg!(-d --device [DEVICE] "Path to the nvram device."))
+        .arg(
+            clap::arg!(-v --verbose "Print diagnostics, repeat for more")
+                .action(clap::ArgAction::Count),
+        )
+        .arg(clap::arg!(--"bluez-config" [CONFIG] "Bluez config path."))
+        .arg(clap::arg!(--"iwd-config" [CONFIG] "IWD config path."))
+        .arg(clap::arg!(--overwrite "Replace config files that differ from nvram."))
+        .arg(clap::arg!(--"no-dbus" "Don't reload bluetoothd or connect Bluetooth devices."))
+        .get_matches();
+    apple_nvram::logger::init(matches.get_count("verbose"));
+
+    let default_name = "/dev/mtd/by-name/nvram".to_owned();
+    let default_bluez_config = "/var/lib/bluetooth".to_owned();
+    let default_iwd_config = "/var/lib/iwd".to_owned();
+
+    let device = matches.get_one::<String>("device").unwrap_or(&default_name);
+    log::debug!("using nvram device {}", device);
+    let mut file = OpenOptions::new().read(true).open(device)?;
+    let data = apple_nvram::read_active_bank(&mut file)?;
+    let mut nv = nvram_parse(&data)?;
+    let active = nv.active_part_mut();
+
+    // a machine that never shared one kind of credential with macos just doesn't have the
+    // variable, that isn't an error
+    let bt = match active.get_variable(BT_VAR.as_bytes(), VarType::System) {
+        Some(var) => asahi_btsync::sync_btkeys(
+            var,
+            matches
+                .get_one::<String>("bluez-config")
+                .unwrap_or(&default_bluez_config),
+            None,
+            matches.contains_id("overwrite"),
+            (!matches.contains_id("no-dbus")).then_some(Duration::from_secs(10)),
+        )
+        .map(Some)
+        .map_err(Error::Bluetooth),
+        None => Ok(None),
+    };
+    let wlan = match active.get_variable(WLAN_VAR.as_bytes(), VarType::System) {
+        Some(var) => asahi_wifisync::sync_wlankeys(
+            var,
+            matches
+                .get_one::<String>("iwd-config")
+                .unwrap_or(&default_iwd_config),
+            true,
+            matches.contains_id("overwrite"),
+        )
+        .map(Some)
+        .map_err(Error::Wlan),
+        None => Ok(None),
+    };
+
+    report("Bluetooth devices", BT_VAR, &bt);
+    report("Wi-Fi networks", WLAN_VAR, &wlan);
+    bt?;
+    wlan?;
+    Ok(())
+}
+
+fn report(what: &str, var: &str, result: &Result<Option<usize>>) {
+    match result {
+        Ok(Some(count)) => println!("Imported {} {}", count, what),
+        Ok(None) => println!("No {} in nvram, {} is not set", what, var),
+        Err(e) => println!("Failed to import {}: {:?}", what, e),
+    }
+}
@@ -0,0 +1,366 @@
+/* SPDX-License-Identifier: MIT */
+
+use std::{fmt::Debug, io, path::Path};
+
+use apple_nvram::{base64_encode, Nvram, VarType, Variable};
+use asahi_common::{json_string, write_ini, FileStatus};
+
+use ini::Ini;
+
+#[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
+pub enum Error {
+    Parse,
+    SectionTooBig,
+    ApplyError(std::io::Error),
+    VariableNotFound,
+    FileIO,
+    IWDConfigDirNotFound,
+}
+
+impl From<apple_nvram::Error> for Error {
+    fn from(e: apple_nvram::Error) -> Self {
+        match e {
+            apple_nvram::Error::ParseError => Error::Parse,
+            apple_nvram::Error::ChecksumMismatch { .. } => Error::Parse,
+            apple_nvram::Error::InvalidBank => Error::Parse,
+            apple_nvram::Error::UnknownFormat { .. } => Error::Parse,
+            apple_nvram::Error::UnsupportedGuid => Error::Parse,
+            apple_nvram::Error::Unsupported => Error::Parse,
+            apple_nvram::Error::Blank => Error::Parse,
+            apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
+            apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::EraseError(e) => Error::ApplyError(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(_e: io::Error) -> Self {
+        Error::FileIO
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, PartialEq)]
+pub enum Secret {
+    // the 32 byte key derived from the passphrase, enough for WPA2 but not for SAE
+    Psk(Vec<u8>),
+    Passphrase(String),
+}
+
+pub struct Network {
+    pub ssid: String,
+    // ssids are arbitrary bytes, ssid above is only for display and file names
+    pub raw_ssid: Vec<u8>,
+    pub secret: Option<Secret>,
+}
+
+// a derived key is random bytes, a passphrase is 8 to 32 printable characters padded with zeroes.
+// a key that happens to look like that is vanishingly unlikely
+fn parse_secret(field: &[u8]) -> Secret {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    let (text, padding) = field.split_at(len);
+    if len >= 8
+        && !padding.is_empty()
+        && padding.iter().all(|&b| b == 0)
+        && text.iter().all(|b| b.is_ascii_graphic() || *b == b' ')
+    {
+        Secret::Passphrase(String::from_utf8_lossy(text).to_string())
+    } else {
+        Secret::Psk(field.to_owned())
+    }
+}
+
+pub const WLAN_VAR: &str = "preferred-networks";
+
+const CHUNK_LEN: usize = 0xc0;
+
+// the record layout is fixed and not versioned, a firmware that changes it has to fail here
+// instead of producing garbage ssids
+pub fn parse_wlan_info(var: &dyn Variable) -> Result<Vec<Network>> {
+    parse_wlan_data(&var.value())
+}
+
+pub fn read_networks(nv: &dyn Nvram) -> Result<Vec<Network>> {
+    let data = nv
+        .get(VarType::System, WLAN_VAR.as_bytes())
+        .ok_or(Error::VariableNotFound)?;
+    parse_wlan_data(&data)
+}
+
+pub fn parse_wlan_data(data: &[u8]) -> Result<Vec<Network>> {
+    let mut nets = Vec::new();
+    if !data.len().is_multiple_of(CHUNK_LEN) {
+        return Err(Error::Parse);
+    }
+    log::debug!("{} networks in {}", data.len() / CHUNK_LEN, WLAN_VAR);
+    for chunk in data.chunks(CHUNK_LEN) {
+        let ssid_len = u32::from_le_bytes(chunk[0xc..0x10].try_into().unwrap()) as usize;
+        // the ssid runs up to the psk
+        if ssid_len > 0xa0 - 0x10 {
+            return Err(Error::Parse);
+        }
+        let raw_ssid = chunk[0x10..0x10 + ssid_len].to_vec();
+        let ssid = String::from_utf8_lossy(&raw_ssid).to_string();
+        let secure = u32::from_le_bytes(chunk[0x8..0xc].try_into().unwrap()) != 0;
+        let secret = if secure {
+            Some(parse_secret(&chunk[0xa0..0xc0]))
+        } else {
+            None
+        };
+        nets.push(Network {
+            ssid,
+            raw_ssid,
+            secret,
+        });
+    }
+
+    Ok(nets)
+}
+
+// the value with every psk and passphrase zeroed, so a dump can be shared. ssids are kept
+pub fn scrub_wlan_data(data: &[u8]) -> Result<Vec<u8>> {
+    parse_wlan_data(data)?;
+    let mut out = data.to_vec();
+    for chunk in out.chunks_mut(CHUNK_LEN) {
+        chunk[0xa0..0xc0].fill(0);
+    }
+    Ok(out)
+}
+
+fn format_psk(psk: &[u8]) -> String {
+    psk.iter()
+        .map(|x| format!("{x:02x}"))
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+// psks are hidden unless asked for, to keep them out of scrollback and logs
+pub fn print_wlankeys(var: &dyn Variable, show_secrets: bool) -> Result<()> {
+    let info = parse_wlan_info(var)?;
+
+    for network in info {
+        let psk_str = match network.secret {
+            Some(Secret::Psk(psk)) if show_secrets => format!("PSK {}", format_psk(&psk)),
+            Some(Secret::Psk(_)) => "PSK <redacted>".to_owned(),
+            Some(Secret::Passphrase(p)) if show_secrets => format!("Passphrase {}", p),
+            Some(Secret::Passphrase(_)) => "Passphrase <redacted>".to_owned(),
+            None => "Open".to_owned(),
+        };
+        println!("SSID {}, {}", network.ssid, psk_str);
+    }
+    Ok(())
+}
+
+// one object per network. the records don't say whether a network is hidden, so hidden is
+// always null. ssids that aren't utf-8 also get the exact bytes as ssid_base64
+pub fn format_wlankeys_json(nets: &[Network], show_secrets: bool) -> String {
+    let mut out = Vec::new();
+    for net in nets {
+        let ssid_base64 = if std::str::from_utf8(&net.raw_ssid).is_err() {
+            format!(", \"ssid_base64\": \"{}\"", base64_encode(&net.raw_ssid))
+        } else {
+            String::new()
+        };
+        let (security, secret) = match &net.secret {
+            Some(Secret::Psk(psk)) => ("psk", format!(", \"psk\": \"{}\"", format_psk(psk))),
+            Some(Secret::Passphrase(p)) => ("psk", format!(", \"passphrase\": {}", json_string(p))),
+            None => ("open", String::new()),
+        };
+        out.push(format!(
+            "  {{\"ssid\": {}{}, \"security\": \"{}\", \"hidden\": null, \"has_psk\": {}{}}}",
+            json_string(&net.ssid),
+            ssid_base64,
+            security,
+            net.secret.is_some(),
+            if show_secrets { secret } else { String::new() }
+        ));
+    }
+    if out.is_empty() {
+        return "[]".to_owned();
+    }
+    format!("[\n{}\n]", out.join(",\n"))
+}
+
+// returns how many networks were created or updated, existing configs are only replaced with
+// overwrite
+pub fn sync_wlankeys(
+    var: &dyn Variable,
+    config: &String,
+    autoconnect: bool,
+    overwrite: bool,
+) -> Result<usize> {
+    let config_path = Path::new(config);
+
+    if !config_path.is_dir() {
+        return Err(Error::IWDConfigDirNotFound);
+    }
+    let nets = parse_wlan_info(var)?;
+    let mut added = 0;
+
+    for net in nets {
+        let suffix = if net.secret.is_some() {
+            ".psk"
+        } else {
+            ".open"
+        };
+        let net_path = config_path.join(format!("{}{}", net.ssid, suffix));
+
+        // the records don't say whether a network is hidden, so Hidden= is left to iwd's default
+        let mut info = Ini::new();
+        info.with_section(Some("Settings"))
+            .set("AutoConnect", autoconnect.to_string());
+        match net.secret {
+            Some(Secret::Psk(psk)) => {
+                info.with_section(Some("Security"))
+                    .set("PreSharedKey", format_psk(&psk));
+            }
+            // iwd derives the key itself, and SAE networks can't connect without the passphrase
+            Some(Secret::Passphrase(p)) => {
+                info.with_section(Some("Security")).set("Passphrase", p);
+            }
+            None => {}
+        }
+        let status = write_ini(&info, &net_path, overwrite)?;
+
+        println!("{}: {}", net.ssid, status);
+        if matches!(status, FileStatus::Created | FileStatus::Updated) {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apple_nvram::nvram_parse;
+
+    fn network(ssid: &[u8], psk: Option<[u8; 32]>) -> Vec<u8> {
+        let mut chunk = vec![0; CHUNK_LEN];
+        chunk[0x8..0xc].copy_from_slice(&(psk.is_some() as u32).to_le_bytes());
+        chunk[0xc..0x10].copy_from_slice(&(ssid.len() as u32).to_le_bytes());
+        chunk[0x10..0x10 + ssid.len()].copy_from_slice(ssid);
+        if let Some(psk) = psk {
+            chunk[0xa0..0xc0].copy_from_slice(&psk);
+        }
+        chunk
+    }
+
+    fn parse_from_image(value: Vec<u8>) -> Result<Vec<Network>> {
+        let image =
+            apple_nvram::v3::Nvram::with_variable(VarType::System, b"preferred-networks", &value)?;
+        let nv = nvram_parse(&image)?;
+        read_networks(nv.as_ref())
+    }
+
+    #[test]
+    fn test_parse_wlan_info_from_image() -> Result<()> {
+        let mut passphrase = [0; 32];
+        passphrase[..13].copy_from_slice(b"correct horse");
+        let nets = parse_from_image(
+            [
+                network(b"home", Some([0x11; 32])),
+                network(b"cafe", None),
+                network(b"office", Some(passphrase)),
+            ]
+            .concat(),
+        )?;
+        assert_eq!(nets.len(), 3);
+        assert_eq!(nets[0].ssid, "home");
+        assert_eq!(nets[0].secret, Some(Secret::Psk(vec![0x11; 32])));
+        assert_eq!(nets[1].ssid, "cafe");
+        assert_eq!(nets[1].secret, None);
+        assert_eq!(nets[2].ssid, "office");
+        assert_eq!(
+            nets[2].secret,
+            Some(Secret::Passphrase("correct horse".to_owned()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrub_wlan_data() -> Result<()> {
+        let mut passphrase = [0; 32];
+        passphrase[..13].copy_from_slice(b"correct horse");
+        let value = [
+            network(b"home", Some([0x11; 32])),
+            network(b"cafe", None),
+            network(b"office", Some(passphrase)),
+        ]
+        .concat();
+        let scrubbed = scrub_wlan_data(&value)?;
+        assert_eq!(scrubbed.len(), value.len());
+        let nets = parse_wlan_data(&scrubbed)?;
+        assert_eq!(nets.len(), 3);
+        assert_eq!(nets[2].ssid, "office");
+        assert_eq!(nets[0].secret, Some(Secret::Psk(vec![0; 32])));
+        assert_eq!(nets[1].secret, None);
+        assert_eq!(nets[2].secret, Some(Secret::Psk(vec![0; 32])));
+        assert!(scrub_wlan_data(&value[1..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_wlan_info_empty() -> Result<()> {
+        assert!(parse_from_image(Vec::new())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_wlan_info_rejects_other_layouts() {
+        let mut value = network(b"home", None);
+        value.truncate(CHUNK_LEN - 0x10);
+        assert!(matches!(parse_from_image(value), Err(Error::Parse)));
+
+        let mut value = network(b"home", None);
+        value[0xc..0x10].copy_from_slice(&0x91u32.to_le_bytes());
+        assert!(matches!(parse_from_image(value), Err(Error::Parse)));
+    }
+
+    #[test]
+    fn test_format_wlankeys_json() -> Result<()> {
+        let mut passphrase = [0; 32];
+        passphrase[..9].copy_from_slice(b"say \"hi\"!");
+        let nets = parse_wlan_data(
+            &[
+                network(b"home", Some([0x11; 32])),
+                network(b"caf\xe9", None),
+                network(b"office", Some(passphrase)),
+            ]
+            .concat(),
+        )?;
+        assert_eq!(
+            format_wlankeys_json(&nets, false),
+            "[\n  {\"ssid\": \"home\", \"security\": \"psk\", \"hidden\": null, \"has_psk\": true},\n  \
+             {\"ssid\": \"caf\u{fffd}\", \"ssid_base64\": \"Y2Fm6Q==\", \"security\": \"open\", \"hidden\": null, \
+             \"has_psk\": false},\n  \
+             {\"ssid\": \"office\", \"security\": \"psk\", \"hidden\": null, \"has_psk\": true}\n]"
+        );
+        let json = format_wlankeys_json(&nets, true);
+        assert!(json.contains(&format!("\"psk\": \"{}\"", "11".repeat(32))));
+        assert!(json.contains("\"passphrase\": \"say \\\"hi\\\"!\""));
+        assert_eq!(format_wlankeys_json(&[], true), "[]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_secret() {
+        let mut field = [0; 32];
+        field[..8].copy_from_slice(b"password");
+        assert_eq!(
+            parse_secret(&field),
+            Secret::Passphrase("password".to_owned())
+        );
+        // too short to be a passphrase
+        field[7] = 0;
+        assert!(matches!(parse_secret(&field), Secret::Psk(_)));
+        // a full field has no terminator, so it's a key even if printable
+        assert!(matches!(parse_secret(&[b'a'; 32]), Secret::Psk(_)));
+        let mut field = [0x11; 32];
+        field[31] = 0;
+        assert!(matches!(parse_secret(&field), Secret::Psk(_)));
+    }
+}
@@ -0,0 +1,8 @@
+// SPDX-License-Identifier: MIT
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = asahi_btsync::parse_bt_data(data);
+});
@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: MIT
+#![no_main]
+
+use apple_nvram::{nvram_parse, MemoryWriter};
+use libfuzzer_sys::fuzz_target;
+
+// anything that parses has to survive being read back and rewritten too
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut nv) = nvram_parse(data) else {
+        return;
+    };
+    for v in nv.to_owned_snapshot() {
+        let _ = v.format(None);
+    }
+    nv.prepare_for_write();
+    let _ = nv.apply(&mut MemoryWriter::new(data.to_vec()));
+});